@@ -0,0 +1,211 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+use crate::cpu::{Cpu, KBD, RAM_SIZE, SCREEN};
+use crate::instruction::Instruction;
+
+const RECENT_INSTRUCTIONS: usize = 8;
+// The stack is RAM[256..2047], SP points one past its top and so is 2048 when it is full.
+const STACK_BASE: u16 = 256;
+const STACK_FULL: u16 = 2048;
+
+#[derive(PartialEq, Debug)]
+pub enum TrapKind {
+    UnmappedWrite(u16),
+    KeyboardWrite,
+    UninitializedRead(u16),
+    StackPointer(u16),
+    LeftProgram(u16),
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrapKind::UnmappedWrite(address) => {
+                write!(f, "write to unmapped address {}", address)
+            }
+            TrapKind::KeyboardWrite => write!(f, "write to the read-only keyboard register"),
+            TrapKind::UninitializedRead(address) => {
+                write!(f, "read of uninitialized RAM[{}]", address)
+            }
+            TrapKind::StackPointer(sp) => {
+                write!(
+                    f,
+                    "stack pointer {} left the stack region {}..{} ({} is a full stack)",
+                    sp,
+                    STACK_BASE,
+                    STACK_FULL - 1,
+                    STACK_FULL
+                )
+            }
+            TrapKind::LeftProgram(target) => {
+                write!(
+                    f,
+                    "execution continued at {}, outside of the loaded program",
+                    target
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub pc: u16,
+    pub label: Option<String>,
+    pub recent: Vec<(u16, Instruction)>,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trap at PC={}", self.pc)?;
+        if let Some(label) = &self.label {
+            write!(f, " ({})", label)?;
+        }
+        writeln!(f, ": {}", self.kind)?;
+        writeln!(f, "Last instructions:")?;
+        for (address, instruction) in &self.recent {
+            writeln!(f, "  {:>5}  {}", address, instruction)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Trap {}
+
+// Collects the ROM address of every `(LABEL)` in an assembly file, sorted by address.
+pub fn parse_labels(asm: &str) -> Vec<(u16, String)> {
+    let mut labels = Vec::new();
+    let mut address: u16 = 0;
+    for line in asm.split('\n') {
+        let line = match line.find("//") {
+            Some(idx) => line.split_at(idx).0.trim(),
+            None => line.trim(),
+        };
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('(') && line.ends_with(')') {
+            labels.push((address, String::from(&line[1..line.len() - 1])));
+        } else {
+            address += 1;
+        }
+    }
+    labels
+}
+
+// Opt-in runtime checks around `Cpu::step`, for catching bugs in generated code early.
+pub struct Checker {
+    initialized: Vec<bool>,
+    sp_armed: bool,
+    recent: VecDeque<u16>,
+    labels: Vec<(u16, String)>,
+}
+
+impl Checker {
+    // Words that are already non-zero, e.g. test inputs, count as initialized.
+    pub fn build(cpu: &Cpu, labels: Vec<(u16, String)>) -> Checker {
+        let mut initialized: Vec<bool> = cpu.ram[..SCREEN as usize]
+            .iter()
+            .map(|word| *word != 0)
+            .collect();
+        // The bootstrap code saves LCL, ARG, THIS and THAT before it ever sets them.
+        for flag in initialized.iter_mut().take(5) {
+            *flag = true;
+        }
+
+        Checker {
+            initialized,
+            sp_armed: false,
+            recent: VecDeque::with_capacity(RECENT_INSTRUCTIONS),
+            labels,
+        }
+    }
+
+    pub fn mark_initialized(&mut self, address: u16) {
+        if address < SCREEN {
+            self.initialized[address as usize] = true;
+        }
+    }
+
+    pub fn run(&mut self, cpu: &mut Cpu, max_cycles: u64) -> Result<u64, Box<Trap>> {
+        let start = cpu.cycles;
+        let limit = start.saturating_add(max_cycles);
+        while cpu.cycles < limit && !cpu.halted {
+            let pc = cpu.pc;
+            self.before(cpu, pc)?;
+            cpu.step();
+            self.after(cpu, pc)?;
+        }
+        Ok(cpu.cycles - start)
+    }
+
+    fn before(&mut self, cpu: &Cpu, pc: u16) -> Result<(), Box<Trap>> {
+        if self.recent.len() == RECENT_INSTRUCTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(pc);
+
+        if let Instruction::C { comp, dest, .. } = cpu.instruction(pc) {
+            let address = cpu.a;
+            if comp.uses_m() && address < SCREEN && !self.initialized[address as usize] {
+                return Err(self.trap(cpu, pc, TrapKind::UninitializedRead(address)));
+            }
+            if dest.m {
+                if address == KBD {
+                    return Err(self.trap(cpu, pc, TrapKind::KeyboardWrite));
+                }
+                // The CPU drops bit 15, so addresses from 32768 on land on RAM or the screen.
+                if address > KBD && (address as usize >= RAM_SIZE || !cpu.is_mapped(address)) {
+                    return Err(self.trap(cpu, pc, TrapKind::UnmappedWrite(address)));
+                }
+                self.mark_initialized(address);
+            }
+        }
+        Ok(())
+    }
+
+    fn after(&mut self, cpu: &Cpu, pc: u16) -> Result<(), Box<Trap>> {
+        // SP is only watched once the program has set it up, plain assembly may use RAM[0] freely.
+        let sp = cpu.ram[0];
+        if (STACK_BASE..=STACK_FULL).contains(&sp) {
+            self.sp_armed = true;
+        } else if self.sp_armed {
+            return Err(self.trap(cpu, pc, TrapKind::StackPointer(sp)));
+        }
+
+        if cpu.pc as usize >= cpu.program_len() {
+            return Err(self.trap(cpu, pc, TrapKind::LeftProgram(cpu.pc)));
+        }
+        Ok(())
+    }
+
+    pub fn nearest_label(&self, address: u16) -> Option<String> {
+        let idx = self
+            .labels
+            .partition_point(|(label_address, _)| *label_address <= address);
+        if idx == 0 {
+            return None;
+        }
+        let (label_address, name) = &self.labels[idx - 1];
+        if *label_address == address {
+            return Some(name.clone());
+        }
+        Some(format!("{}+{}", name, address - label_address))
+    }
+
+    fn trap(&self, cpu: &Cpu, pc: u16, kind: TrapKind) -> Box<Trap> {
+        Box::new(Trap {
+            kind,
+            pc,
+            label: self.nearest_label(pc),
+            recent: self
+                .recent
+                .iter()
+                .map(|address| (*address, cpu.instruction(*address)))
+                .collect(),
+        })
+    }
+}
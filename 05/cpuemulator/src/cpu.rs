@@ -0,0 +1,204 @@
+use std::error::Error;
+
+use crate::instruction::Instruction;
+use crate::peripheral::{Keyboard, Peripheral, Screen};
+
+pub const ROM_SIZE: usize = 32768;
+pub const RAM_SIZE: usize = 32768;
+pub const SCREEN: u16 = 16384;
+pub const KBD: u16 = 24576;
+
+const NO_DEVICE: u8 = u8::MAX;
+
+pub struct Cpu {
+    // The whole ROM is decoded up front, unused words decode to @0 like on the real chip.
+    rom: Vec<Instruction>,
+    program_len: usize,
+    pub ram: Vec<u16>,
+    peripherals: Vec<Box<dyn Peripheral>>,
+    // Peripheral index per address, only consulted at or above `io_base`.
+    io_map: Vec<u8>,
+    io_base: usize,
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub cycles: u64,
+    pub halted: bool,
+}
+
+impl Cpu {
+    pub fn build(program: &[u16]) -> Cpu {
+        let mut rom = vec![Instruction::A(0); ROM_SIZE];
+        for (idx, word) in program.iter().enumerate() {
+            rom[idx] = Instruction::decode(*word);
+        }
+
+        let mut cpu = Cpu {
+            rom,
+            program_len: program.len(),
+            ram: vec![0; RAM_SIZE],
+            peripherals: Vec::new(),
+            io_map: vec![NO_DEVICE; RAM_SIZE],
+            io_base: RAM_SIZE,
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            halted: false,
+        };
+        cpu.attach(Box::new(Screen::new())).unwrap();
+        cpu.attach(Box::<Keyboard>::default()).unwrap();
+        cpu
+    }
+
+    pub fn attach(&mut self, device: Box<dyn Peripheral>) -> Result<(), Box<dyn Error>> {
+        let base = device.base() as usize;
+        let end = base + device.size() as usize;
+        if end > RAM_SIZE {
+            return Err(format!("{} does not fit into the address space", device.name()).into());
+        }
+        if let Some(address) = (base..end).find(|address| self.io_map[*address] != NO_DEVICE) {
+            let other = self.peripherals[self.io_map[address] as usize].name();
+            return Err(format!(
+                "{} overlaps {} at address {}",
+                device.name(),
+                other,
+                address
+            )
+            .into());
+        }
+        if self.peripherals.len() >= NO_DEVICE as usize {
+            return Err("too many peripherals".into());
+        }
+
+        for address in base..end {
+            self.io_map[address] = self.peripherals.len() as u8;
+        }
+        self.io_base = self.io_base.min(base);
+        self.peripherals.push(device);
+        Ok(())
+    }
+
+    pub fn peripheral<T: 'static>(&self) -> Option<&T> {
+        self.peripherals
+            .iter()
+            .find_map(|device| device.as_any().downcast_ref::<T>())
+    }
+
+    pub fn peripheral_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.peripherals
+            .iter_mut()
+            .find_map(|device| device.as_any_mut().downcast_mut::<T>())
+    }
+
+    pub fn is_mapped(&self, address: u16) -> bool {
+        self.io_map[address as usize & (RAM_SIZE - 1)] != NO_DEVICE
+    }
+
+    // Addresses not claimed by a peripheral behave like plain RAM.
+    #[inline(always)]
+    pub fn read(&mut self, address: u16) -> u16 {
+        let address = address as usize & (RAM_SIZE - 1);
+        if address < self.io_base {
+            return self.ram[address];
+        }
+        match self.io_map[address] {
+            NO_DEVICE => self.ram[address],
+            idx => {
+                let device = &mut self.peripherals[idx as usize];
+                let offset = address as u16 - device.base();
+                device.read(offset, self.cycles)
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn write(&mut self, address: u16, value: u16) {
+        let address = address as usize & (RAM_SIZE - 1);
+        if address < self.io_base {
+            self.ram[address] = value;
+            return;
+        }
+        match self.io_map[address] {
+            NO_DEVICE => self.ram[address] = value,
+            idx => {
+                let device = &mut self.peripherals[idx as usize];
+                let offset = address as u16 - device.base();
+                device.write(offset, value, self.cycles);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.halted = false;
+    }
+
+    pub fn program_len(&self) -> usize {
+        self.program_len
+    }
+
+    pub fn instruction(&self, address: u16) -> Instruction {
+        self.rom[address as usize & (ROM_SIZE - 1)]
+    }
+
+    pub fn set_key(&mut self, key: u16) {
+        if let Some(keyboard) = self.peripheral_mut::<Keyboard>() {
+            keyboard.key = key;
+        }
+    }
+
+    #[inline(always)]
+    pub fn step(&mut self) {
+        let pc = self.pc;
+        match self.rom[pc as usize & (ROM_SIZE - 1)] {
+            Instruction::A(value) => {
+                self.a = value;
+                self.pc = pc.wrapping_add(1);
+            }
+            Instruction::C { comp, dest, jump } => {
+                // The memory address and the jump target are the A value from before this instruction.
+                let target = self.a;
+                let y = if comp.uses_m() {
+                    self.read(target)
+                } else {
+                    target
+                };
+                let value = comp.eval(self.d, y);
+
+                if dest.m {
+                    self.write(target, value);
+                }
+                if dest.a {
+                    self.a = value;
+                }
+                if dest.d {
+                    self.d = value;
+                }
+
+                if jump.taken(value) {
+                    // `(END) @END 0;JMP` is the conventional way to stop a Hack program.
+                    if target.wrapping_add(1) == pc
+                        && self.rom[target as usize & (ROM_SIZE - 1)] == Instruction::A(target)
+                    {
+                        self.halted = true;
+                    }
+                    self.pc = target;
+                } else {
+                    self.pc = pc.wrapping_add(1);
+                }
+            }
+        }
+        self.cycles += 1;
+    }
+
+    // Runs until the program halts or `max_cycles` more instructions have executed.
+    pub fn run(&mut self, max_cycles: u64) -> u64 {
+        let start = self.cycles;
+        let limit = start.saturating_add(max_cycles);
+        while self.cycles < limit && !self.halted {
+            self.step();
+        }
+        self.cycles - start
+    }
+}
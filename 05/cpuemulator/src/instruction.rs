@@ -0,0 +1,296 @@
+use std::error::Error;
+use std::fmt;
+
+// ALU computations, decoded once from the a-bit and c1..c6 bits.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Comp {
+    Zero,
+    One,
+    NegOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    DPlusOne,
+    APlusOne,
+    MPlusOne,
+    DMinusOne,
+    AMinusOne,
+    MMinusOne,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+    // Control bits outside the documented table, evaluated bit by bit.
+    Alu { bits: u8, uses_m: bool },
+}
+
+impl Comp {
+    pub fn decode(a_bit: bool, bits: u8) -> Comp {
+        match (a_bit, bits) {
+            (_, 0b101010) => Comp::Zero,
+            (_, 0b111111) => Comp::One,
+            (_, 0b111010) => Comp::NegOne,
+            (_, 0b001100) => Comp::D,
+            (false, 0b110000) => Comp::A,
+            (true, 0b110000) => Comp::M,
+            (_, 0b001101) => Comp::NotD,
+            (false, 0b110001) => Comp::NotA,
+            (true, 0b110001) => Comp::NotM,
+            (_, 0b001111) => Comp::NegD,
+            (false, 0b110011) => Comp::NegA,
+            (true, 0b110011) => Comp::NegM,
+            (_, 0b011111) => Comp::DPlusOne,
+            (false, 0b110111) => Comp::APlusOne,
+            (true, 0b110111) => Comp::MPlusOne,
+            (_, 0b001110) => Comp::DMinusOne,
+            (false, 0b110010) => Comp::AMinusOne,
+            (true, 0b110010) => Comp::MMinusOne,
+            (false, 0b000010) => Comp::DPlusA,
+            (true, 0b000010) => Comp::DPlusM,
+            (false, 0b010011) => Comp::DMinusA,
+            (true, 0b010011) => Comp::DMinusM,
+            (false, 0b000111) => Comp::AMinusD,
+            (true, 0b000111) => Comp::MMinusD,
+            (false, 0b000000) => Comp::DAndA,
+            (true, 0b000000) => Comp::DAndM,
+            (false, 0b010101) => Comp::DOrA,
+            (true, 0b010101) => Comp::DOrM,
+            (uses_m, bits) => Comp::Alu { bits, uses_m },
+        }
+    }
+
+    pub fn uses_m(&self) -> bool {
+        match self {
+            Comp::M
+            | Comp::NotM
+            | Comp::NegM
+            | Comp::MPlusOne
+            | Comp::MMinusOne
+            | Comp::DPlusM
+            | Comp::DMinusM
+            | Comp::MMinusD
+            | Comp::DAndM
+            | Comp::DOrM => true,
+            Comp::Alu { uses_m, .. } => *uses_m,
+            _ => false,
+        }
+    }
+
+    // y is either A or M, depending on the a-bit of the instruction.
+    #[inline(always)]
+    pub fn eval(&self, d: u16, y: u16) -> u16 {
+        match self {
+            Comp::Zero => 0,
+            Comp::One => 1,
+            Comp::NegOne => 0xFFFF,
+            Comp::D => d,
+            Comp::A | Comp::M => y,
+            Comp::NotD => !d,
+            Comp::NotA | Comp::NotM => !y,
+            Comp::NegD => d.wrapping_neg(),
+            Comp::NegA | Comp::NegM => y.wrapping_neg(),
+            Comp::DPlusOne => d.wrapping_add(1),
+            Comp::APlusOne | Comp::MPlusOne => y.wrapping_add(1),
+            Comp::DMinusOne => d.wrapping_sub(1),
+            Comp::AMinusOne | Comp::MMinusOne => y.wrapping_sub(1),
+            Comp::DPlusA | Comp::DPlusM => d.wrapping_add(y),
+            Comp::DMinusA | Comp::DMinusM => d.wrapping_sub(y),
+            Comp::AMinusD | Comp::MMinusD => y.wrapping_sub(d),
+            Comp::DAndA | Comp::DAndM => d & y,
+            Comp::DOrA | Comp::DOrM => d | y,
+            Comp::Alu { bits, .. } => alu(d, y, *bits),
+        }
+    }
+
+    fn mnemonic(&self) -> String {
+        let mnemonic = match self {
+            Comp::Zero => "0",
+            Comp::One => "1",
+            Comp::NegOne => "-1",
+            Comp::D => "D",
+            Comp::A => "A",
+            Comp::M => "M",
+            Comp::NotD => "!D",
+            Comp::NotA => "!A",
+            Comp::NotM => "!M",
+            Comp::NegD => "-D",
+            Comp::NegA => "-A",
+            Comp::NegM => "-M",
+            Comp::DPlusOne => "D+1",
+            Comp::APlusOne => "A+1",
+            Comp::MPlusOne => "M+1",
+            Comp::DMinusOne => "D-1",
+            Comp::AMinusOne => "A-1",
+            Comp::MMinusOne => "M-1",
+            Comp::DPlusA => "D+A",
+            Comp::DPlusM => "D+M",
+            Comp::DMinusA => "D-A",
+            Comp::DMinusM => "D-M",
+            Comp::AMinusD => "A-D",
+            Comp::MMinusD => "M-D",
+            Comp::DAndA => "D&A",
+            Comp::DAndM => "D&M",
+            Comp::DOrA => "D|A",
+            Comp::DOrM => "D|M",
+            Comp::Alu { bits, uses_m } => {
+                return format!("alu{}[{:06b}]", if *uses_m { "M" } else { "A" }, bits)
+            }
+        };
+        String::from(mnemonic)
+    }
+}
+
+// The Hack ALU as specified by the zx, nx, zy, ny, f and no control bits.
+pub fn alu(x: u16, y: u16, bits: u8) -> u16 {
+    let mut x = if bits & 0b100000 != 0 { 0 } else { x };
+    if bits & 0b010000 != 0 {
+        x = !x;
+    }
+    let mut y = if bits & 0b001000 != 0 { 0 } else { y };
+    if bits & 0b000100 != 0 {
+        y = !y;
+    }
+    let out = if bits & 0b000010 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if bits & 0b000001 != 0 {
+        return !out;
+    }
+    out
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Jump {
+    Never,
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+impl Jump {
+    pub fn decode(bits: u8) -> Jump {
+        match bits & 0b111 {
+            0b000 => Jump::Never,
+            0b001 => Jump::JGT,
+            0b010 => Jump::JEQ,
+            0b011 => Jump::JGE,
+            0b100 => Jump::JLT,
+            0b101 => Jump::JNE,
+            0b110 => Jump::JLE,
+            _ => Jump::JMP,
+        }
+    }
+
+    #[inline(always)]
+    pub fn taken(&self, value: u16) -> bool {
+        let value = value as i16;
+        match self {
+            Jump::Never => false,
+            Jump::JGT => value > 0,
+            Jump::JEQ => value == 0,
+            Jump::JGE => value >= 0,
+            Jump::JLT => value < 0,
+            Jump::JNE => value != 0,
+            Jump::JLE => value <= 0,
+            Jump::JMP => true,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Dest {
+    pub a: bool,
+    pub d: bool,
+    pub m: bool,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Instruction {
+    A(u16),
+    C { comp: Comp, dest: Dest, jump: Jump },
+}
+
+impl Instruction {
+    pub fn decode(word: u16) -> Instruction {
+        if word & 0x8000 == 0 {
+            return Instruction::A(word);
+        }
+
+        let comp = Comp::decode(word & 0x1000 != 0, ((word >> 6) & 0b111111) as u8);
+        let dest = Dest {
+            a: word & 0b100000 != 0,
+            d: word & 0b010000 != 0,
+            m: word & 0b001000 != 0,
+        };
+        let jump = Jump::decode((word & 0b111) as u8);
+        Instruction::C { comp, dest, jump }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::A(value) => write!(f, "@{}", value),
+            Instruction::C { comp, dest, jump } => {
+                let mut dest_str = String::new();
+                if dest.a {
+                    dest_str.push('A');
+                }
+                if dest.m {
+                    dest_str.push('M');
+                }
+                if dest.d {
+                    dest_str.push('D');
+                }
+                if !dest_str.is_empty() {
+                    write!(f, "{}=", dest_str)?;
+                }
+                write!(f, "{}", comp.mnemonic())?;
+                if *jump != Jump::Never {
+                    write!(f, ";{:?}", jump)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Parses the textual .hack format: one 16 character binary word per line.
+pub fn parse_hack(contents: &str) -> Result<Vec<u16>, Box<dyn Error>> {
+    let mut words: Vec<u16> = Vec::new();
+    for (idx, line) in contents.split('\n').enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() != 16 {
+            return Err(format!("line {}: expected 16 bits, got '{}'", idx + 1, line).into());
+        }
+        let word = u16::from_str_radix(line, 2)
+            .map_err(|_| format!("line {}: invalid binary word '{}'", idx + 1, line))?;
+        words.push(word);
+    }
+
+    if words.len() > 32768 {
+        return Err(format!("program has {} words, ROM holds 32768", words.len()).into());
+    }
+    Ok(words)
+}
@@ -0,0 +1,199 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::Instant,
+};
+
+use check::Checker;
+use cpu::Cpu;
+use peripheral::{Console, Timer};
+
+pub mod check;
+pub mod cpu;
+pub mod instruction;
+pub mod peripheral;
+#[cfg(test)]
+mod tests;
+
+pub struct Config {
+    pub in_file: Option<String>,
+    pub max_cycles: u64,
+    pub bench: bool,
+    pub console: bool,
+    pub timer: bool,
+    pub check: bool,
+    pub symbols: Option<String>,
+}
+
+impl Config {
+    pub fn build(args: &[String]) -> Result<Config, &'static str> {
+        let mut in_file = None;
+        let mut max_cycles = u64::MAX;
+        let mut bench = false;
+        let mut console = false;
+        let mut timer = false;
+        let mut check = false;
+        let mut symbols = None;
+
+        let mut idx = 1;
+        while idx < args.len() {
+            match args[idx].as_str() {
+                "--bench" => bench = true,
+                "--console" => console = true,
+                "--timer" => timer = true,
+                "--check" => check = true,
+                "--symbols" => {
+                    idx += 1;
+                    symbols = match args.get(idx) {
+                        Some(path) => Some(path.clone()),
+                        None => return Err("--symbols expects an asm path!"),
+                    };
+                }
+                "--cycles" => {
+                    idx += 1;
+                    max_cycles = match args.get(idx).map(|arg| arg.parse::<u64>()) {
+                        Some(Ok(cycles)) => cycles,
+                        _ => return Err("--cycles expects a number!"),
+                    };
+                }
+                arg if arg.starts_with("--") => return Err("Unknown option!"),
+                _ => {
+                    if in_file.is_some() {
+                        return Err("Not correct number of arguments!");
+                    }
+                    in_file = Some(args[idx].clone());
+                }
+            }
+            idx += 1;
+        }
+
+        if in_file.is_none() && !bench {
+            return Err("Not correct number of arguments!");
+        }
+
+        Ok(Config {
+            in_file,
+            max_cycles,
+            bench,
+            console,
+            timer,
+            check,
+            symbols,
+        })
+    }
+}
+
+// Increments RAM[0] and accumulates it into RAM[1] forever: a mix of ALU, memory and jump work.
+pub const BENCH_PROGRAM: [u16; 7] = [
+    0b0000000000000000, // @0
+    0b1111110111001000, // M=M+1
+    0b1111110000010000, // D=M
+    0b0000000000000001, // @1
+    0b1111000010001000, // M=D+M
+    0b0000000000000000, // @0
+    0b1110101010000111, // 0;JMP
+];
+
+pub struct BenchResult {
+    pub cycles: u64,
+    pub seconds: f64,
+}
+
+impl BenchResult {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.cycles as f64 / self.seconds
+    }
+}
+
+pub fn bench(program: &[u16], cycles: u64) -> BenchResult {
+    let mut cpu = Cpu::build(program);
+    let start = Instant::now();
+    let mut executed = 0;
+    // Restart halted programs so that short ones still produce a meaningful figure.
+    while executed < cycles {
+        executed += cpu.run(cycles - executed);
+        if cpu.halted {
+            cpu.reset();
+        }
+    }
+    BenchResult {
+        cycles: executed,
+        seconds: start.elapsed().as_secs_f64(),
+    }
+}
+
+// Labels come from --symbols, or from the .asm file next to the .hack file if there is one.
+fn symbols_file(config: &Config) -> Option<String> {
+    if config.symbols.is_some() {
+        return config.symbols.clone();
+    }
+    let asm = Path::new(config.in_file.as_ref()?).with_extension("asm");
+    if asm.is_file() {
+        return Some(asm.to_string_lossy().to_string());
+    }
+    None
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let program = match &config.in_file {
+        Some(in_file) => instruction::parse_hack(fs::read_to_string(in_file)?.as_str())?,
+        None => BENCH_PROGRAM.to_vec(),
+    };
+
+    if config.bench {
+        let cycles = if config.max_cycles == u64::MAX {
+            100_000_000
+        } else {
+            config.max_cycles
+        };
+        let result = bench(&program, cycles);
+        println!(
+            "Executed {} instructions in {:.3}s: {:.1} MIPS",
+            result.cycles,
+            result.seconds,
+            result.instructions_per_second() / 1_000_000.0
+        );
+        return Ok(());
+    }
+
+    let mut cpu = Cpu::build(&program);
+    if config.console {
+        cpu.attach(Box::new(Console::new(true)))?;
+    }
+    if config.timer {
+        cpu.attach(Box::<Timer>::default())?;
+    }
+    if config.check {
+        let labels = match symbols_file(&config) {
+            Some(path) => check::parse_labels(fs::read_to_string(path)?.as_str()),
+            None => Vec::new(),
+        };
+        let mut checker = Checker::build(&cpu, labels);
+        checker.run(&mut cpu, config.max_cycles)?;
+    } else {
+        cpu.run(config.max_cycles);
+    }
+
+    let mut summary = String::new();
+    if cpu
+        .peripheral::<Console>()
+        .is_some_and(|console| !console.output.is_empty())
+    {
+        summary.push('\n');
+    }
+    if cpu.halted {
+        summary += &format!("Halted after {} cycles\n", cpu.cycles);
+    } else {
+        summary += &format!("Stopped after {} cycles\n", cpu.cycles);
+    }
+    summary += &format!("PC={} A={} D={}\n", cpu.pc, cpu.a, cpu.d as i16);
+    for (address, value) in cpu.ram.iter().take(16).enumerate() {
+        summary += &format!("RAM[{}] = {}\n", address, *value as i16);
+    }
+    // Like the console echo, a closed stdout is no error of the program.
+    let _ = io::stdout().write_all(summary.as_bytes());
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use cpuemulator::Config;
+use std::{env, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        println!("Problem parsing arguments: {err}");
+        println!("Program format: <input hack path> [--cycles N] [--bench] [--console] [--timer] [--check [--symbols asm path]]");
+        process::exit(1);
+    });
+
+    if let Err(e) = cpuemulator::run(config) {
+        println!("Application error: {e}");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,181 @@
+use std::any::Any;
+use std::io::Write;
+
+use crate::cpu::{KBD, SCREEN};
+
+// A memory-mapped device that owns the words `base..base + size` of the data memory.
+// Offsets passed to `read` and `write` are relative to `base`.
+pub trait Peripheral {
+    fn name(&self) -> &str;
+    fn base(&self) -> u16;
+    fn size(&self) -> u16;
+    fn read(&mut self, offset: u16, cycles: u64) -> u16;
+    fn write(&mut self, offset: u16, value: u16, cycles: u64);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+pub const SCREEN_WORDS: u16 = 8192;
+
+pub struct Screen {
+    pub words: Vec<u16>,
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen {
+            words: vec![0; SCREEN_WORDS as usize],
+        }
+    }
+
+    pub fn pixel(&self, row: usize, col: usize) -> bool {
+        let word = self.words[row * 32 + col / 16];
+        word & (1 << (col % 16)) != 0
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+impl Peripheral for Screen {
+    fn name(&self) -> &str {
+        "SCREEN"
+    }
+    fn base(&self) -> u16 {
+        SCREEN
+    }
+    fn size(&self) -> u16 {
+        SCREEN_WORDS
+    }
+    fn read(&mut self, offset: u16, _cycles: u64) -> u16 {
+        self.words[offset as usize]
+    }
+    fn write(&mut self, offset: u16, value: u16, _cycles: u64) {
+        self.words[offset as usize] = value;
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Holds the code of the currently pressed key, 0 when no key is pressed. Writes are ignored.
+#[derive(Default)]
+pub struct Keyboard {
+    pub key: u16,
+}
+
+impl Peripheral for Keyboard {
+    fn name(&self) -> &str {
+        "KBD"
+    }
+    fn base(&self) -> u16 {
+        KBD
+    }
+    fn size(&self) -> u16 {
+        1
+    }
+    fn read(&mut self, _offset: u16, _cycles: u64) -> u16 {
+        self.key
+    }
+    fn write(&mut self, _offset: u16, _value: u16, _cycles: u64) {}
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub const CONSOLE: u16 = 24577;
+
+// Debug output: every word written is appended as a character, e.g. `do Memory.poke(24577, 65);`.
+pub struct Console {
+    pub output: String,
+    pub echo: bool,
+}
+
+impl Console {
+    pub fn new(echo: bool) -> Console {
+        Console {
+            output: String::new(),
+            echo,
+        }
+    }
+}
+
+impl Peripheral for Console {
+    fn name(&self) -> &str {
+        "CONSOLE"
+    }
+    fn base(&self) -> u16 {
+        CONSOLE
+    }
+    fn size(&self) -> u16 {
+        1
+    }
+    fn read(&mut self, _offset: u16, _cycles: u64) -> u16 {
+        0
+    }
+    fn write(&mut self, _offset: u16, value: u16, _cycles: u64) {
+        // The Jack character set maps newline to 128.
+        let c = match value {
+            128 => '\n',
+            _ => char::from_u32(value as u32).unwrap_or('?'),
+        };
+        self.output.push(c);
+        if self.echo {
+            // A closed stdout, e.g. piped into `head`, ends the echo but not the program.
+            let mut stdout = std::io::stdout();
+            if write!(stdout, "{}", c)
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                self.echo = false;
+            }
+        }
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub const TIMER: u16 = 24578;
+
+// Reads return the number of instructions executed since the last write, truncated to 16 bits.
+#[derive(Default)]
+pub struct Timer {
+    start: u64,
+}
+
+impl Peripheral for Timer {
+    fn name(&self) -> &str {
+        "TIMER"
+    }
+    fn base(&self) -> u16 {
+        TIMER
+    }
+    fn size(&self) -> u16 {
+        1
+    }
+    fn read(&mut self, _offset: u16, cycles: u64) -> u16 {
+        (cycles - self.start) as u16
+    }
+    fn write(&mut self, _offset: u16, _value: u16, cycles: u64) {
+        self.start = cycles;
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,162 @@
+use crate::check::{self, Checker, Trap, TrapKind};
+use crate::cpu::{Cpu, KBD, SCREEN};
+use crate::instruction::{self, Comp, Instruction};
+use crate::peripheral::{Console, Keyboard, Screen, Timer, CONSOLE, TIMER};
+
+const MAX_HACK: &str = include_str!("../../Max.hack");
+
+fn run_max(first: u16, second: u16) -> Cpu {
+    let program = instruction::parse_hack(MAX_HACK).unwrap();
+    let mut cpu = Cpu::build(&program);
+    cpu.ram[0] = first;
+    cpu.ram[1] = second;
+    cpu.run(1000);
+    cpu
+}
+
+#[test]
+fn test_max_program() {
+    let cpu = run_max(3, 5);
+    assert!(cpu.halted);
+    assert_eq!(cpu.ram[2], 5);
+
+    let cpu = run_max(23456, 12345);
+    assert!(cpu.halted);
+    assert_eq!(cpu.ram[2], 23456);
+}
+
+#[test]
+fn test_decoded_comp_matches_alu() {
+    let operands: [(u16, u16); 4] = [(0, 0), (17, 3), (0xFFFF, 1), (0x8000, 0x7FFF)];
+    for a_bit in [false, true] {
+        for bits in 0..64u8 {
+            let comp = Comp::decode(a_bit, bits);
+            assert!(!comp.uses_m() || a_bit);
+            for (d, y) in operands {
+                assert_eq!(
+                    comp.eval(d, y),
+                    instruction::alu(d, y, bits),
+                    "{:06b}",
+                    bits
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_disassemble() {
+    assert_eq!(Instruction::decode(0b0000000000010001).to_string(), "@17");
+    assert_eq!(Instruction::decode(0b1111110111001000).to_string(), "M=M+1");
+    assert_eq!(Instruction::decode(0b1110001100000001).to_string(), "D;JGT");
+    assert_eq!(
+        Instruction::decode(0b1111000010111000).to_string(),
+        "AMD=D+M"
+    );
+}
+
+#[test]
+fn test_parse_hack_errors() {
+    assert!(instruction::parse_hack("0000000000000000\n1111\n").is_err());
+    assert!(instruction::parse_hack("000000000000000x\n").is_err());
+}
+
+#[test]
+fn test_rect_draws_through_screen() {
+    let program = instruction::parse_hack(include_str!("../../Rect.hack")).unwrap();
+    let mut cpu = Cpu::build(&program);
+    cpu.ram[0] = 4;
+    cpu.run(10000);
+    assert!(cpu.halted);
+
+    let screen = cpu.peripheral::<Screen>().unwrap();
+    for row in 0..4 {
+        assert_eq!(screen.words[row * 32], 0xFFFF);
+    }
+    assert_eq!(screen.words[4 * 32], 0);
+    assert!(screen.pixel(3, 15));
+    assert!(!screen.pixel(3, 16));
+    // The screen is no longer backed by RAM.
+    assert_eq!(cpu.ram[SCREEN as usize], 0);
+}
+
+#[test]
+fn test_keyboard_console_and_timer() {
+    let mut cpu = Cpu::build(&[]);
+    cpu.attach(Box::new(Console::new(false))).unwrap();
+    cpu.attach(Box::<Timer>::default()).unwrap();
+
+    cpu.set_key(75);
+    assert_eq!(cpu.read(KBD), 75);
+    cpu.write(KBD, 1);
+    assert_eq!(cpu.read(KBD), 75);
+
+    cpu.write(CONSOLE, 'H' as u16);
+    cpu.write(CONSOLE, 'i' as u16);
+    cpu.write(CONSOLE, 128);
+    assert_eq!(cpu.peripheral::<Console>().unwrap().output, "Hi\n");
+
+    cpu.write(TIMER, 0);
+    cpu.run(5);
+    assert_eq!(cpu.read(TIMER), 5);
+}
+
+#[test]
+fn test_overlapping_peripherals_are_rejected() {
+    let mut cpu = Cpu::build(&[]);
+    assert!(cpu.attach(Box::<Keyboard>::default()).is_err());
+}
+
+const M_ZERO: u16 = 0b1110101010001000;
+const D_M: u16 = 0b1111110000010000;
+const D_A: u16 = 0b1110110000010000;
+const M_D: u16 = 0b1110001100001000;
+const JMP: u16 = 0b1110101010000111;
+const A_MINUS_ONE: u16 = 0b1110111010100000;
+const A_NOT_A: u16 = 0b1110110001100000;
+
+fn run_checked(program: &[u16]) -> Result<u64, Box<Trap>> {
+    let mut cpu = Cpu::build(program);
+    let mut checker = Checker::build(&cpu, check::parse_labels("(START)\n@1\n(LOOP)\nD=M\n"));
+    checker.run(&mut cpu, 100)
+}
+
+#[test]
+fn test_check_traps() {
+    let trap = run_checked(&[30000, M_ZERO]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::UnmappedWrite(30000));
+    assert_eq!(trap.pc, 1);
+    assert_eq!(trap.label.as_deref(), Some("LOOP"));
+    assert_eq!(trap.recent.len(), 2);
+
+    // The CPU drops bit 15, so A=-1 would write to 32767 and !16383 to the screen.
+    let trap = run_checked(&[A_MINUS_ONE, M_ZERO]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::UnmappedWrite(65535));
+    let trap = run_checked(&[16383, A_NOT_A, M_ZERO]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::UnmappedWrite(49152));
+
+    let trap = run_checked(&[KBD, M_ZERO]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::KeyboardWrite);
+
+    let trap = run_checked(&[100, D_M]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::UninitializedRead(100));
+
+    let trap = run_checked(&[256, D_A, 0, M_D, 0, M_ZERO]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::StackPointer(0));
+    assert_eq!(trap.label.as_deref(), Some("LOOP+4"));
+    // A full stack is fine, one more push is not.
+    let push_to = |sp: u16| run_checked(&[256, D_A, 0, M_D, sp, D_A, 0, M_D, 8, JMP]);
+    assert_eq!(push_to(2048).unwrap(), 10);
+    let trap = push_to(2049).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::StackPointer(2049));
+    assert_eq!(
+        trap.kind.to_string(),
+        "stack pointer 2049 left the stack region 256..2047 (2048 is a full stack)"
+    );
+
+    let trap = run_checked(&[5, JMP]).unwrap_err();
+    assert_eq!(trap.kind, TrapKind::LeftProgram(5));
+
+    // Initialized memory, valid writes and a proper halt loop run clean.
+    assert_eq!(run_checked(&[100, M_ZERO, 100, D_M, 4, JMP]).unwrap(), 6);
+}
@@ -0,0 +1,103 @@
+// Chips implemented natively by the simulator. Their interfaces are declared in HDL, in the
+// same form as the built-in chips of the Java Hardware Simulator.
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Builtin {
+    Dff,
+    Bit,
+    Register,
+    Ram(usize),
+    Rom32K,
+    Screen,
+    Keyboard,
+    PC,
+}
+
+const DFF: &str = "CHIP DFF { IN in; OUT out; BUILTIN DFF; CLOCKED in; }";
+const BIT: &str = "CHIP Bit { IN in, load; OUT out; BUILTIN Bit; CLOCKED in, load; }";
+const REGISTER: &str =
+    "CHIP Register { IN in[16], load; OUT out[16]; BUILTIN Register; CLOCKED in, load; }";
+const A_REGISTER: &str =
+    "CHIP ARegister { IN in[16], load; OUT out[16]; BUILTIN ARegister; CLOCKED in, load; }";
+const D_REGISTER: &str =
+    "CHIP DRegister { IN in[16], load; OUT out[16]; BUILTIN DRegister; CLOCKED in, load; }";
+const RAM8: &str =
+    "CHIP RAM8 { IN in[16], load, address[3]; OUT out[16]; BUILTIN RAM8; CLOCKED in, load; }";
+const RAM64: &str =
+    "CHIP RAM64 { IN in[16], load, address[6]; OUT out[16]; BUILTIN RAM64; CLOCKED in, load; }";
+const RAM512: &str =
+    "CHIP RAM512 { IN in[16], load, address[9]; OUT out[16]; BUILTIN RAM512; CLOCKED in, load; }";
+const RAM4K: &str =
+    "CHIP RAM4K { IN in[16], load, address[12]; OUT out[16]; BUILTIN RAM4K; CLOCKED in, load; }";
+const RAM16K: &str =
+    "CHIP RAM16K { IN in[16], load, address[14]; OUT out[16]; BUILTIN RAM16K; CLOCKED in, load; }";
+const ROM32K: &str = "CHIP ROM32K { IN address[15]; OUT out[16]; BUILTIN ROM32K; }";
+const SCREEN: &str =
+    "CHIP Screen { IN in[16], load, address[13]; OUT out[16]; BUILTIN Screen; CLOCKED in, load; }";
+const KEYBOARD: &str = "CHIP Keyboard { OUT out[16]; BUILTIN Keyboard; }";
+const PC: &str =
+    "CHIP PC { IN in[16], load, inc, reset; OUT out[16]; BUILTIN PC; CLOCKED in, load, inc, reset; }";
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        let builtin = match name {
+            "DFF" => Builtin::Dff,
+            "Bit" => Builtin::Bit,
+            "Register" | "ARegister" | "DRegister" => Builtin::Register,
+            "RAM8" => Builtin::Ram(3),
+            "RAM64" => Builtin::Ram(6),
+            "RAM512" => Builtin::Ram(9),
+            "RAM4K" => Builtin::Ram(12),
+            "RAM16K" => Builtin::Ram(14),
+            "ROM32K" => Builtin::Rom32K,
+            "Screen" => Builtin::Screen,
+            "Keyboard" => Builtin::Keyboard,
+            "PC" => Builtin::PC,
+            _ => return None,
+        };
+        Some(builtin)
+    }
+
+    pub fn source(name: &str) -> Option<&'static str> {
+        let source = match name {
+            "DFF" => DFF,
+            "Bit" => BIT,
+            "Register" => REGISTER,
+            "ARegister" => A_REGISTER,
+            "DRegister" => D_REGISTER,
+            "RAM8" => RAM8,
+            "RAM64" => RAM64,
+            "RAM512" => RAM512,
+            "RAM4K" => RAM4K,
+            "RAM16K" => RAM16K,
+            "ROM32K" => ROM32K,
+            "Screen" => SCREEN,
+            "Keyboard" => KEYBOARD,
+            "PC" => PC,
+            _ => return None,
+        };
+        Some(source)
+    }
+
+    // Number of 16-bit words of state.
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Builtin::Ram(address_bits) => 1 << address_bits,
+            Builtin::Rom32K => 32768,
+            Builtin::Screen => 8192,
+            _ => 1,
+        }
+    }
+
+    // Inputs that reach the outputs without waiting for the clock.
+    pub fn is_combinational_input(&self, pin: &str) -> bool {
+        match self {
+            Builtin::Ram(_) | Builtin::Rom32K | Builtin::Screen => pin == "address",
+            _ => false,
+        }
+    }
+
+    pub fn is_clocked(&self) -> bool {
+        !matches!(self, Builtin::Rom32K | Builtin::Keyboard)
+    }
+}
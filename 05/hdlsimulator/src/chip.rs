@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::builtin::Builtin;
+use crate::parser;
+
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PinDecl {
+    pub name: String,
+    pub width: usize,
+    pub location: Location,
+}
+
+// `name`, `name[i]` or `name[i..j]` on either side of a connection.
+#[derive(Clone, Debug)]
+pub struct PinRef {
+    pub name: String,
+    pub range: Option<(usize, usize)>,
+    pub location: Location,
+}
+
+impl PinRef {
+    pub fn is_constant(&self) -> bool {
+        self.name == "true" || self.name == "false"
+    }
+
+    // Bits selected out of a pin of the given width.
+    pub fn bits(&self, width: usize) -> std::ops::Range<usize> {
+        match self.range {
+            Some((from, to)) => from..to + 1,
+            None => 0..width,
+        }
+    }
+}
+
+impl fmt::Display for PinRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.range {
+            Some((from, to)) if from == to => write!(f, "{}[{}]", self.name, from),
+            Some((from, to)) => write!(f, "{}[{}..{}]", self.name, from, to),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub pin: PinRef,
+    pub signal: PinRef,
+}
+
+#[derive(Clone, Debug)]
+pub struct Part {
+    pub name: String,
+    pub connections: Vec<Connection>,
+    pub location: Location,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChipDef {
+    pub name: String,
+    pub inputs: Vec<PinDecl>,
+    pub outputs: Vec<PinDecl>,
+    pub parts: Vec<Part>,
+    // Set for `BUILTIN Name;` chips, which are implemented by the simulator.
+    pub builtin: Option<String>,
+    pub location: Location,
+}
+
+impl ChipDef {
+    pub fn input(&self, name: &str) -> Option<&PinDecl> {
+        self.inputs.iter().find(|pin| pin.name == name)
+    }
+
+    pub fn output(&self, name: &str) -> Option<&PinDecl> {
+        self.outputs.iter().find(|pin| pin.name == name)
+    }
+
+    pub fn pin(&self, name: &str) -> Option<&PinDecl> {
+        self.input(name).or_else(|| self.output(name))
+    }
+
+    pub fn is_primitive(&self) -> bool {
+        self.name == "Nand"
+    }
+
+    fn nand() -> ChipDef {
+        let pin = |name: &str| PinDecl {
+            name: String::from(name),
+            width: 1,
+            location: Location::default(),
+        };
+        ChipDef {
+            name: String::from("Nand"),
+            inputs: vec![pin("a"), pin("b")],
+            outputs: vec![pin("out")],
+            parts: Vec::new(),
+            builtin: None,
+            location: Location::default(),
+        }
+    }
+}
+
+// Resolves chip names to definitions: the Nand primitive, then `Name.hdl` in the search
+// directories, in order, then the built-in chips. With `prefer_builtins` the built-in
+// chips come before the search directories, except for the chip under test. So do the chips
+// in `builtin_chips` without it.
+pub struct Library {
+    search_paths: Vec<PathBuf>,
+    pub prefer_builtins: bool,
+    pub builtin_chips: Vec<&'static str>,
+    chips: RefCell<HashMap<String, Rc<ChipDef>>>,
+}
+
+impl Library {
+    pub fn build(search_paths: Vec<PathBuf>) -> Library {
+        Library {
+            search_paths,
+            prefer_builtins: false,
+            builtin_chips: Vec::new(),
+            chips: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
+    }
+
+    pub fn find_file(&self, name: &str) -> Option<PathBuf> {
+        self.search_paths
+            .iter()
+            .map(|dir| dir.join(format!("{}.hdl", name)))
+            .find(|path| path.is_file())
+    }
+
+    pub fn load(&self, name: &str) -> Result<Rc<ChipDef>, Box<dyn Error>> {
+        if let Some(def) = self.chips.borrow().get(name) {
+            return Ok(def.clone());
+        }
+
+        let def = match Builtin::source(name) {
+            _ if name == "Nand" => ChipDef::nand(),
+            Some(source) if self.prefer_builtins || self.builtin_chips.contains(&name) => {
+                parser::parse(source, "<builtin>")?
+            }
+            source => match self.find_file(name) {
+                Some(path) => self.load_path(&path, name)?,
+                None => match source {
+                    Some(source) => parser::parse(source, "<builtin>")?,
+                    None => return Err(format!("chip {} not found", name).into()),
+                },
+            },
+        };
+
+        let def = Rc::new(def);
+        self.chips
+            .borrow_mut()
+            .insert(String::from(name), def.clone());
+        Ok(def)
+    }
+
+    // The chip under test always comes from its HDL file when there is one.
+    pub fn load_top(&self, name: &str) -> Result<Rc<ChipDef>, Box<dyn Error>> {
+        match self.find_file(name) {
+            Some(path) => Ok(Rc::new(self.load_path(&path, name)?)),
+            None => self.load(name),
+        }
+    }
+
+    fn load_path(&self, path: &Path, name: &str) -> Result<ChipDef, Box<dyn Error>> {
+        let def = load_file(path)?;
+        if def.name != name {
+            return Err(format!(
+                "{}: file defines chip {}, expected {}",
+                path.display(),
+                def.name,
+                name
+            )
+            .into());
+        }
+        Ok(def)
+    }
+}
+
+pub fn load_file(path: &Path) -> Result<ChipDef, Box<dyn Error>> {
+    let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    parser::parse(&source, &path.display().to_string())
+}
@@ -0,0 +1,153 @@
+use std::error::Error;
+
+use cpuemulator::cpu::Cpu;
+use cpuemulator::instruction::Instruction;
+
+use crate::netlist::{Net, Netlist};
+use crate::simulator::Simulator;
+
+// The RAM chips `--hack` always takes built in: RAM16K from Nand gates is millions of gates
+// and does not fit into memory.
+pub const MEMORY_CHIPS: [&str; 5] = ["RAM8", "RAM64", "RAM512", "RAM4K", "RAM16K"];
+
+// The gate-level computer next to the emulator, both starting from reset with the same ROM.
+struct CoSimulation {
+    simulator: Simulator,
+    cpu: Cpu,
+    // Pins of the CPU part: the program counter and the memory write port.
+    pc: Vec<Net>,
+    write_m: Vec<Net>,
+    address_m: Vec<Net>,
+    out_m: Vec<Net>,
+}
+
+// The pin of the top level CPU part, e.g. scope ["Computer", "CPU_2"].
+fn cpu_pin(netlist: &Netlist, name: &str) -> Result<Vec<Net>, Box<dyn Error>> {
+    netlist
+        .signals
+        .iter()
+        .find(|signal| {
+            signal.scope.len() == 2 && signal.scope[1].starts_with("CPU_") && signal.name == name
+        })
+        .map(|signal| signal.nets.clone())
+        .ok_or_else(|| format!("chip {} has no CPU part with pin {}", netlist.name, name).into())
+}
+
+impl CoSimulation {
+    fn register(&self, name: &str) -> Result<u16, Box<dyn Error>> {
+        self.simulator
+            .memory(name)
+            .map(|memory| memory[0])
+            .ok_or_else(|| format!("chip has no {} part", name).into())
+    }
+
+    // The write the emulator's next instruction makes, from the A value before it.
+    fn expected_write(&mut self) -> Option<(u16, u16)> {
+        match self.cpu.instruction(self.cpu.pc) {
+            Instruction::C { comp, dest, .. } if dest.m => {
+                let address = self.cpu.a;
+                let y = match comp.uses_m() {
+                    true => self.cpu.read(address),
+                    false => address,
+                };
+                Some((address & 0x7fff, comp.eval(self.cpu.d, y)))
+            }
+            _ => None,
+        }
+    }
+
+    fn compare(&mut self, cycle: u64) -> Result<(), Box<dyn Error>> {
+        let registers = [
+            // The hardware PC has 15 bits, like the ROM address.
+            (
+                "PC",
+                self.simulator.read_nets(&self.pc) as u16,
+                self.cpu.pc & 0x7fff,
+            ),
+            ("A", self.register("ARegister")?, self.cpu.a),
+            ("D", self.register("DRegister")?, self.cpu.d),
+        ];
+        for (name, gates, emulator) in registers {
+            if gates != emulator {
+                return Err(format!(
+                    "cycle {}: {} is {} on the gate level, {} in the emulator",
+                    cycle, name, gates, emulator
+                )
+                .into());
+            }
+        }
+
+        let write = match self.simulator.read_nets(&self.write_m) {
+            1 => Some((
+                self.simulator.read_nets(&self.address_m) as u16,
+                self.simulator.read_nets(&self.out_m) as u16,
+            )),
+            _ => None,
+        };
+        let expected = self.expected_write();
+        if write != expected {
+            let describe = |write: Option<(u16, u16)>| match write {
+                Some((address, value)) => format!("writes {} to RAM[{}]", value as i16, address),
+                None => String::from("writes nothing"),
+            };
+            return Err(format!(
+                "cycle {}: the gate level {}, the emulator {}",
+                cycle,
+                describe(write),
+                describe(expected)
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+// Runs a program on the gate-level computer and on the emulator in lockstep, checking
+// PC, A, D and the memory write of every cycle. Stops when the emulator sees the program
+// halt or after `max_cycles`, returning the number of cycles run. `ram` presets words of the
+// data memory, which needs the built-in RAM16K on the gate level, see `MEMORY_CHIPS`.
+pub fn run_program(
+    netlist: Netlist,
+    program: &[u16],
+    ram: &[(u16, u16)],
+    max_cycles: u64,
+) -> Result<u64, Box<dyn Error>> {
+    let mut co = CoSimulation {
+        pc: cpu_pin(&netlist, "pc")?,
+        write_m: cpu_pin(&netlist, "writeM")?,
+        address_m: cpu_pin(&netlist, "addressM")?,
+        out_m: cpu_pin(&netlist, "outM")?,
+        simulator: Simulator::build(netlist),
+        cpu: Cpu::build(program),
+    };
+    let rom = co
+        .simulator
+        .memory_mut("ROM32K")
+        .ok_or("chip has no ROM32K part")?;
+    rom[..program.len()].copy_from_slice(program);
+    if !ram.is_empty() {
+        let memory = co
+            .simulator
+            .memory_mut("RAM16K")
+            .ok_or("presetting RAM needs the built-in RAM16K, try --builtin")?;
+        for (address, value) in ram {
+            let word = memory
+                .get_mut(*address as usize)
+                .ok_or_else(|| format!("RAM[{}] is outside of RAM16K", address))?;
+            *word = *value;
+            co.cpu.write(*address, *value);
+        }
+    }
+
+    let mut cycle = 0;
+    while cycle < max_cycles && !co.cpu.halted {
+        co.simulator.eval();
+        co.compare(cycle)?;
+        co.simulator.tick();
+        co.simulator.tock();
+        co.cpu.step();
+        cycle += 1;
+    }
+    co.compare(cycle)?;
+    Ok(cycle)
+}
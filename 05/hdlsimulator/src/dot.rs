@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::chip::{ChipDef, Library, PinDecl, PinRef};
+
+struct Exporter<'a> {
+    library: &'a Library,
+    lines: Vec<String>,
+}
+
+// `name`, `name[width]` for whole buses, or the sub-bus as written.
+fn label(signal: &PinRef, width: usize) -> String {
+    match (signal.range, width) {
+        (Some(_), _) | (None, 1) => signal.to_string(),
+        (None, width) => format!("{}[{}]", signal.name, width),
+    }
+}
+
+// Record label with the input pins on the left and the outputs on the right.
+fn record(def: &ChipDef) -> String {
+    let ports = |pins: &[PinDecl]| -> String {
+        pins.iter()
+            .map(|pin| format!("<{0}>{0}", pin.name))
+            .collect::<Vec<String>>()
+            .join("|")
+    };
+    format!(
+        "{{{{{}}}|{}|{{{}}}}}",
+        ports(&def.inputs),
+        def.name,
+        ports(&def.outputs)
+    )
+}
+
+impl Exporter<'_> {
+    fn push(&mut self, depth: usize, line: String) {
+        self.lines.push(format!("{}{}", "    ".repeat(depth), line));
+    }
+
+    // Emits the pins and parts of a chip, expanding the parts built from other parts
+    // `levels` deep. Node names are prefixed so every expanded instance is unique.
+    fn chip(
+        &mut self,
+        def: &ChipDef,
+        prefix: &str,
+        depth: usize,
+        levels: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let shape = if prefix.is_empty() {
+            "ellipse"
+        } else {
+            "plaintext"
+        };
+        let mut widths: HashMap<&str, usize> = HashMap::new();
+        // Driving endpoints per signal with the reference they drive.
+        let mut drivers: HashMap<&str, Vec<(String, &PinRef)>> = HashMap::new();
+        // Reading endpoints, chip outputs take the reference of each driver instead.
+        let mut readers: Vec<(&str, Option<&PinRef>, String)> = Vec::new();
+        let whole: Vec<PinRef> = def
+            .inputs
+            .iter()
+            .map(|pin| PinRef {
+                name: pin.name.clone(),
+                range: None,
+                location: pin.location.clone(),
+            })
+            .collect();
+        for (pin, signal) in def.inputs.iter().zip(whole.iter()) {
+            let node = format!("{}in_{}", prefix, pin.name);
+            self.push(
+                depth,
+                format!("{} [label=\"{}\", shape={}];", node, pin.name, shape),
+            );
+            widths.insert(&pin.name, pin.width);
+            drivers.insert(&pin.name, vec![(node, signal)]);
+        }
+        for pin in &def.outputs {
+            let node = format!("{}out_{}", prefix, pin.name);
+            self.push(
+                depth,
+                format!("{} [label=\"{}\", shape={}];", node, pin.name, shape),
+            );
+            widths.insert(&pin.name, pin.width);
+            readers.push((&pin.name, None, node));
+        }
+
+        for (idx, part) in def.parts.iter().enumerate() {
+            let child = self
+                .library
+                .load(&part.name)
+                .map_err(|err| format!("{}: {}", part.location, err))?;
+            let node = format!("{}p{}", prefix, idx);
+            let expand = levels > 0 && !child.is_primitive() && child.builtin.is_none();
+            if expand {
+                self.push(depth, format!("subgraph cluster_{} {{", node));
+                self.push(depth + 1, format!("label=\"{}\";", child.name));
+                self.chip(&child, &format!("{}_", node), depth + 1, levels - 1)?;
+                self.push(depth, String::from("}"));
+            } else {
+                self.push(
+                    depth,
+                    format!("{} [label=\"{}\", shape=record];", node, record(&child)),
+                );
+            }
+
+            for conn in &part.connections {
+                let pin = child.pin(&conn.pin.name).ok_or_else(|| {
+                    format!(
+                        "{}: chip {} has no pin {}",
+                        conn.pin.location, child.name, conn.pin.name
+                    )
+                })?;
+                if conn.signal.is_constant() {
+                    continue;
+                }
+                let name = conn.signal.name.as_str();
+                if child.output(&pin.name).is_some() {
+                    let endpoint = match expand {
+                        true => format!("{}_out_{}", node, pin.name),
+                        false => format!("{}:{}:e", node, pin.name),
+                    };
+                    widths
+                        .entry(name)
+                        .or_insert_with(|| conn.pin.bits(pin.width).len());
+                    drivers
+                        .entry(name)
+                        .or_default()
+                        .push((endpoint, &conn.signal));
+                } else {
+                    let endpoint = match expand {
+                        true => format!("{}_in_{}", node, pin.name),
+                        false => format!("{}:{}:w", node, pin.name),
+                    };
+                    readers.push((name, Some(&conn.signal), endpoint));
+                }
+            }
+        }
+
+        // Pins without a driver and constants get no edge.
+        for (name, read, endpoint) in &readers {
+            let width = widths.get(name).copied().unwrap_or(1);
+            for (driver, driven) in drivers.get(name).into_iter().flatten() {
+                let signal = read.unwrap_or(driven);
+                self.push(
+                    depth,
+                    format!(
+                        "{} -> {} [label=\"{}\"];",
+                        driver,
+                        endpoint,
+                        label(signal, width)
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Draws the chip's parts as nodes and its pins as edges labeled with their widths, for
+// Graphviz. Parts made of other parts are drawn as clusters of their own parts `levels` deep.
+pub fn export(library: &Library, name: &str, levels: usize) -> Result<String, Box<dyn Error>> {
+    let def = library.load_top(name)?;
+    let mut exporter = Exporter {
+        library,
+        lines: Vec::new(),
+    };
+    exporter.push(0, format!("digraph {} {{", def.name));
+    exporter.push(1, String::from("rankdir=LR;"));
+    exporter.chip(&def, "", 1, levels)?;
+    exporter.push(0, String::from("}"));
+    Ok(exporter.lines.join("\n") + "\n")
+}
@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::netlist::{Netlist, Port};
+use crate::simulator::Simulator;
+
+// Chips with at most this many input bits are checked on every input combination.
+const EXHAUSTIVE_BITS: usize = 20;
+// Clock cycles per random input sequence of a sequential chip.
+const CYCLES: usize = 16;
+
+// An input sequence on which the chips disagree. Combinational chips take a single step.
+pub struct Counterexample {
+    pub steps: Vec<Vec<(String, u64)>>,
+    // Whether the difference showed after tick/tock rather than after eval.
+    pub clocked: bool,
+    pub output: String,
+    pub left: u64,
+    pub right: u64,
+}
+
+pub enum Verdict {
+    Equivalent { vectors: u64, exhaustive: bool },
+    Different(Counterexample),
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Equivalent {
+                vectors,
+                exhaustive: true,
+            } => write!(f, "equivalent on all {} input combinations", vectors),
+            Verdict::Equivalent { vectors, .. } => {
+                write!(f, "no difference found in {} random input vectors", vectors)
+            }
+            Verdict::Different(example) => {
+                writeln!(f, "not equivalent, counterexample:")?;
+                for (idx, step) in example.steps.iter().enumerate() {
+                    let inputs: Vec<String> = step
+                        .iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect();
+                    match example.steps.len() {
+                        1 => writeln!(f, "  {}", inputs.join(" "))?,
+                        _ => writeln!(f, "  cycle {}: {}", idx, inputs.join(" "))?,
+                    }
+                }
+                write!(
+                    f,
+                    "  {}{}: {} vs {}",
+                    example.output,
+                    if example.clocked { " after tock" } else { "" },
+                    example.left,
+                    example.right
+                )
+            }
+        }
+    }
+}
+
+fn interface(ports: &[Port]) -> Vec<(String, usize)> {
+    let mut pins: Vec<(String, usize)> = ports
+        .iter()
+        .map(|port| (port.name.clone(), port.nets.len()))
+        .collect();
+    pins.sort();
+    pins
+}
+
+fn check_interface(left: &Netlist, right: &Netlist) -> Result<(), Box<dyn Error>> {
+    for (kind, a, b) in [
+        ("inputs", &left.inputs, &right.inputs),
+        ("outputs", &left.outputs, &right.outputs),
+    ] {
+        let (a, b) = (interface(a), interface(b));
+        if a != b {
+            let pins = |pins: &[(String, usize)]| -> Vec<String> {
+                pins.iter()
+                    .map(|(name, width)| format!("{}[{}]", name, width))
+                    .collect()
+            };
+            return Err(format!(
+                "chips have different {}: {} vs {}",
+                kind,
+                pins(&a).join(", "),
+                pins(&b).join(", ")
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// Random values favour the corner cases: zero, all ones and single bits.
+fn random_value(rng: &mut StdRng, width: usize) -> u64 {
+    let mask = u64::MAX >> (64 - width);
+    match rng.gen_range(0..8) {
+        0 => 0,
+        1 => mask,
+        2 => 1 << rng.gen_range(0..width),
+        _ => rng.gen::<u64>() & mask,
+    }
+}
+
+struct Checker {
+    left: Simulator,
+    right: Simulator,
+}
+
+impl Checker {
+    fn set(&mut self, inputs: &[(String, u64)]) -> Result<(), Box<dyn Error>> {
+        for (name, value) in inputs {
+            self.left.set(name, *value)?;
+            self.right.set(name, *value)?;
+        }
+        Ok(())
+    }
+
+    // The first output on which the chips differ.
+    fn compare(&self) -> Option<(String, u64, u64)> {
+        for port in &self.left.netlist.outputs {
+            let other = self.right.netlist.output(&port.name)?;
+            let left = self.left.read_nets(&port.nets);
+            let right = self.right.read_nets(&other.nets);
+            if left != right {
+                return Some((port.name.clone(), left, right));
+            }
+        }
+        None
+    }
+
+    // Applies the steps from a reset state, returning the counterexample if they differ.
+    fn run(
+        &mut self,
+        steps: &[Vec<(String, u64)>],
+        sequential: bool,
+    ) -> Result<Option<Counterexample>, Box<dyn Error>> {
+        if sequential {
+            self.left.reset();
+            self.right.reset();
+        }
+        for (idx, step) in steps.iter().enumerate() {
+            self.set(step)?;
+            self.left.eval();
+            self.right.eval();
+            let mut difference = self.compare().map(|diff| (diff, false));
+            if difference.is_none() && sequential {
+                for simulator in [&mut self.left, &mut self.right] {
+                    simulator.tick();
+                    simulator.tock();
+                }
+                difference = self.compare().map(|diff| (diff, true));
+            }
+            if let Some(((output, left, right), clocked)) = difference {
+                return Ok(Some(Counterexample {
+                    steps: steps[..=idx].to_vec(),
+                    clocked,
+                    output,
+                    left,
+                    right,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Compares two chips with the same pins. Combinational chips with few input bits are
+// checked exhaustively, others on `samples` random input vectors from a fixed seed.
+// Sequential chips get random input sequences starting from the reset state.
+pub fn check(left: Netlist, right: Netlist, samples: u64) -> Result<Verdict, Box<dyn Error>> {
+    check_interface(&left, &right)?;
+    let sequential = left.dff_count + left.memory_count + right.dff_count + right.memory_count > 0;
+    let inputs: Vec<(String, usize)> = left
+        .inputs
+        .iter()
+        .map(|port| (port.name.clone(), port.nets.len()))
+        .collect();
+    let input_bits: usize = inputs.iter().map(|(_, width)| width).sum();
+    let mut checker = Checker {
+        left: Simulator::build(left),
+        right: Simulator::build(right),
+    };
+
+    if !sequential && input_bits <= EXHAUSTIVE_BITS {
+        let vectors = 1u64 << input_bits;
+        for vector in 0..vectors {
+            let mut shift = 0;
+            let step: Vec<(String, u64)> = inputs
+                .iter()
+                .map(|(name, width)| {
+                    let value = vector >> shift & (u64::MAX >> (64 - width));
+                    shift += width;
+                    (name.clone(), value)
+                })
+                .collect();
+            if let Some(example) = checker.run(&[step], false)? {
+                return Ok(Verdict::Different(example));
+            }
+        }
+        return Ok(Verdict::Equivalent {
+            vectors,
+            exhaustive: true,
+        });
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let length = if sequential { CYCLES } else { 1 };
+    let mut vectors = 0;
+    while vectors < samples {
+        let steps: Vec<Vec<(String, u64)>> = (0..length)
+            .map(|_| {
+                inputs
+                    .iter()
+                    .map(|(name, width)| (name.clone(), random_value(&mut rng, *width)))
+                    .collect()
+            })
+            .collect();
+        if let Some(example) = checker.run(&steps, sequential)? {
+            return Ok(Verdict::Different(example));
+        }
+        vectors += length as u64;
+    }
+    Ok(Verdict::Equivalent {
+        vectors,
+        exhaustive: false,
+    })
+}
@@ -0,0 +1,415 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chip::Library;
+use lint::Severity;
+use netlist::Netlist;
+use script::Outcome;
+use simulator::Simulator;
+use vcd::{VcdConfig, VcdWriter};
+
+pub mod builtin;
+pub mod chip;
+pub mod cosim;
+pub mod dot;
+pub mod equiv;
+pub mod lint;
+pub mod netlist;
+pub mod parser;
+pub mod report;
+pub mod script;
+pub mod simulator;
+#[cfg(test)]
+mod tests;
+pub mod tokenizer;
+pub mod vcd;
+pub mod verilog;
+
+// Cycle limit for `--hack` runs without `--cycles`, programs usually halt long before.
+const COSIM_CYCLES: u64 = 1_000_000;
+
+pub struct Config {
+    pub in_file: String,
+    pub lib_dirs: Vec<String>,
+    pub assignments: Vec<(String, String)>,
+    pub builtins: bool,
+    pub cycles: u64,
+    pub test: bool,
+    pub report: bool,
+    pub verilog: Option<String>,
+    pub dot: Option<String>,
+    pub expand: usize,
+    pub lint: bool,
+    pub vcd: Option<VcdConfig>,
+    pub equiv: Option<String>,
+    pub samples: u64,
+    pub hack: Option<String>,
+}
+
+impl Config {
+    pub fn build(args: &[String]) -> Result<Config, &'static str> {
+        let mut in_file = None;
+        let mut lib_dirs = Vec::new();
+        let mut assignments = Vec::new();
+        let mut builtins = false;
+        let mut cycles = 0;
+        let mut test = false;
+        let mut report = false;
+        let mut verilog = None;
+        let mut dot = None;
+        let mut expand = 0;
+        let mut lint = false;
+        let mut vcd: Option<VcdConfig> = None;
+        let mut internals = false;
+        let mut equiv = None;
+        let mut samples = 10_000;
+        let mut hack = None;
+
+        let mut idx = 1;
+        while idx < args.len() {
+            let arg = args[idx].as_str();
+            if arg == "--lib" {
+                idx += 1;
+                match args.get(idx) {
+                    Some(dir) => lib_dirs.push(dir.clone()),
+                    None => return Err("--lib expects a directory!"),
+                }
+            } else if arg == "--test" {
+                test = true;
+            } else if arg == "--report" {
+                report = true;
+            } else if arg == "--verilog" {
+                idx += 1;
+                match args.get(idx) {
+                    Some(path) => verilog = Some(path.clone()),
+                    None => return Err("--verilog expects an output path!"),
+                }
+            } else if arg == "--dot" {
+                idx += 1;
+                match args.get(idx) {
+                    Some(path) => dot = Some(path.clone()),
+                    None => return Err("--dot expects an output path!"),
+                }
+            } else if arg == "--expand" {
+                idx += 1;
+                expand = match args.get(idx).map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) => n,
+                    _ => return Err("--expand expects a number!"),
+                };
+            } else if arg == "--vcd" {
+                idx += 1;
+                match args.get(idx) {
+                    Some(path) => {
+                        vcd = Some(VcdConfig {
+                            path: PathBuf::from(path),
+                            internals: false,
+                        })
+                    }
+                    None => return Err("--vcd expects an output path!"),
+                }
+            } else if arg == "--equiv" {
+                idx += 1;
+                match args.get(idx) {
+                    Some(path) => equiv = Some(path.clone()),
+                    None => return Err("--equiv expects a chip path!"),
+                }
+            } else if arg == "--hack" {
+                idx += 1;
+                match args.get(idx) {
+                    Some(path) => hack = Some(path.clone()),
+                    None => return Err("--hack expects a program path!"),
+                }
+            } else if arg == "--samples" {
+                idx += 1;
+                samples = match args.get(idx).map(|n| n.parse::<u64>()) {
+                    Some(Ok(n)) => n,
+                    _ => return Err("--samples expects a number!"),
+                };
+            } else if arg == "--internal" {
+                internals = true;
+            } else if arg == "--lint" {
+                lint = true;
+            } else if arg == "--builtin" {
+                builtins = true;
+            } else if arg == "--cycles" {
+                idx += 1;
+                cycles = match args.get(idx).map(|n| n.parse::<u64>()) {
+                    Some(Ok(n)) => n,
+                    _ => return Err("--cycles expects a number!"),
+                };
+            } else if arg.starts_with("--") {
+                return Err("Unknown option!");
+            } else if let Some((pin, value)) = arg.split_once('=') {
+                assignments.push((String::from(pin), String::from(value)));
+            } else if in_file.is_none() {
+                in_file = Some(String::from(arg));
+            } else {
+                return Err("Not correct number of arguments!");
+            }
+            idx += 1;
+        }
+
+        if let Some(vcd) = &mut vcd {
+            vcd.internals = internals;
+        } else if internals {
+            return Err("--internal needs --vcd!");
+        }
+
+        match in_file {
+            Some(in_file) => Ok(Config {
+                in_file,
+                lib_dirs,
+                assignments,
+                builtins,
+                cycles,
+                test,
+                report,
+                verilog,
+                dot,
+                expand,
+                lint,
+                vcd,
+                equiv,
+                samples,
+                hack,
+            }),
+            None => Err("Not correct number of arguments!"),
+        }
+    }
+}
+
+// Parses values in the test script notation: decimal, or %B, %X and %D prefixed.
+pub fn parse_value(text: &str) -> Result<i64, Box<dyn Error>> {
+    let (radix, digits) = match text.get(..2) {
+        Some("%B") => (2, &text[2..]),
+        Some("%X") => (16, &text[2..]),
+        Some("%D") => (10, &text[2..]),
+        _ => (10, text),
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("invalid value '{}'", text).into())
+}
+
+// The chip's own directory comes first, so local chips shadow the library directories.
+pub fn library_for(in_file: &str, lib_dirs: &[String]) -> (Library, String) {
+    let path = PathBuf::from(in_file);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut search_paths = vec![dir];
+    search_paths.extend(lib_dirs.iter().map(PathBuf::from));
+    (Library::build(search_paths), name)
+}
+
+// Collects the files with the given extension under `path`, sorted, skipping build
+// directories.
+pub fn find_files(path: &Path, extension: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    let entries = fs::read_dir(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    for entry in entries {
+        let entry = entry?.path();
+        let name = entry.file_name().unwrap_or_default().to_string_lossy();
+        if entry.is_dir() {
+            if name != "target" && !name.starts_with('.') {
+                files.extend(find_files(&entry, extension)?);
+            }
+        } else if entry.extension().is_some_and(|ext| ext == extension) {
+            files.push(entry);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Lints every chip under the given file or directory, looking parts up next to each chip
+// and then in `lib_dirs`.
+pub fn run_lint(path: &str, lib_dirs: &[String]) -> Result<(), Box<dyn Error>> {
+    let (mut errors, mut warnings) = (0, 0);
+    for file in find_files(Path::new(path), "hdl")? {
+        let (library, _) = library_for(&file.to_string_lossy(), lib_dirs);
+        match lint::lint_file(&library, &file) {
+            Ok(diagnostics) => {
+                for diagnostic in diagnostics {
+                    match diagnostic.severity {
+                        Severity::Error => errors += 1,
+                        Severity::Warning => warnings += 1,
+                    }
+                    println!("{}", diagnostic);
+                }
+            }
+            Err(err) => {
+                println!("{}", err);
+                errors += 1;
+            }
+        }
+    }
+    println!("{} errors, {} warnings", errors, warnings);
+
+    if errors > 0 {
+        return Err(format!("{} errors found", errors).into());
+    }
+    Ok(())
+}
+
+// Runs every test script under the given file or directory and reports each result.
+pub fn run_tests(
+    path: &str,
+    lib_dirs: &[String],
+    vcd: Option<&VcdConfig>,
+) -> Result<(), Box<dyn Error>> {
+    let lib_dirs: Vec<PathBuf> = lib_dirs.iter().map(PathBuf::from).collect();
+    let scripts = find_files(Path::new(path), "tst")?;
+    if vcd.is_some() && scripts.len() != 1 {
+        return Err("--vcd needs a single test script".into());
+    }
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for script in scripts {
+        match script::run_script(&script, &lib_dirs, vcd) {
+            Ok(Outcome::Pass) => {
+                println!("PASS {}", script.display());
+                passed += 1;
+            }
+            Ok(Outcome::Skip(reason)) => {
+                println!("SKIP {}: {}", script.display(), reason);
+                skipped += 1;
+            }
+            Ok(Outcome::Fail(message)) => {
+                println!("FAIL {}: {}", script.display(), message);
+                failed += 1;
+            }
+            Err(err) => {
+                println!("FAIL {}", err);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+
+    if failed > 0 {
+        return Err(format!("{} test scripts failed", failed).into());
+    }
+    Ok(())
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    if config.test {
+        return run_tests(&config.in_file, &config.lib_dirs, config.vcd.as_ref());
+    }
+    if config.lint {
+        return run_lint(&config.in_file, &config.lib_dirs);
+    }
+
+    let (mut library, name) = library_for(&config.in_file, &config.lib_dirs);
+    library.prefer_builtins = config.builtins;
+    if let Some(other) = &config.equiv {
+        let (mut other_library, other_name) = library_for(other, &config.lib_dirs);
+        other_library.prefer_builtins = config.builtins;
+        let left = Netlist::build(&library, &name)?;
+        let right = Netlist::build(&other_library, &other_name)?;
+        let verdict = equiv::check(left, right, config.samples)?;
+        println!("{} vs {}: {}", config.in_file, other, verdict);
+        if let equiv::Verdict::Different(_) = verdict {
+            return Err("chips are not equivalent".into());
+        }
+        return Ok(());
+    }
+    if let Some(path) = &config.hack {
+        let contents = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let program = cpuemulator::instruction::parse_hack(&contents)?;
+        let mut ram = Vec::new();
+        for (pin, value) in &config.assignments {
+            let address = pin
+                .strip_prefix("RAM[")
+                .and_then(|pin| pin.strip_suffix(']'))
+                .and_then(|address| address.parse::<u16>().ok())
+                .ok_or_else(|| format!("--hack only presets RAM[address], not {}", pin))?;
+            ram.push((address, parse_value(value)? as u16));
+        }
+        library.builtin_chips = cosim::MEMORY_CHIPS.to_vec();
+        let netlist = Netlist::build_with_signals(&library, &name)?;
+        let max_cycles = match config.cycles {
+            0 => COSIM_CYCLES,
+            cycles => cycles,
+        };
+        let cycles = cosim::run_program(netlist, &program, &ram, max_cycles)?;
+        println!(
+            "{}: {} agrees with the emulator for {} cycles",
+            name, path, cycles
+        );
+        return Ok(());
+    }
+    let netlist = match &config.vcd {
+        Some(vcd) if vcd.internals => Netlist::build_with_signals(&library, &name)?,
+        _ => Netlist::build(&library, &name)?,
+    };
+    if config.report {
+        println!("{}", report::build(&library, &netlist)?);
+        return Ok(());
+    }
+    if let Some(path) = &config.dot {
+        fs::write(path, dot::export(&library, &name, config.expand)?)?;
+        println!("{}: wrote {}", netlist.name, path);
+        return Ok(());
+    }
+    if let Some(path) = &config.verilog {
+        fs::write(path, verilog::export(&library, &name)?)?;
+        println!("{}: wrote {}", netlist.name, path);
+        return Ok(());
+    }
+    println!("{}: {} gates", netlist.name, netlist.gates.len());
+
+    let mut simulator = Simulator::build(netlist);
+    for (pin, value) in &config.assignments {
+        simulator.set(pin, parse_value(value)? as u64)?;
+    }
+    simulator.eval();
+    let mut vcd = match &config.vcd {
+        Some(vcd) => Some(VcdWriter::create(vcd, &simulator)?),
+        None => None,
+    };
+    if let Some(vcd) = &mut vcd {
+        vcd.sample(&simulator, false)?;
+    }
+    for _ in 0..config.cycles {
+        simulator.tick();
+        if let Some(vcd) = &mut vcd {
+            vcd.sample(&simulator, true)?;
+        }
+        simulator.tock();
+        if let Some(vcd) = &mut vcd {
+            vcd.sample(&simulator, false)?;
+        }
+    }
+    if let Some(vcd) = vcd {
+        vcd.finish()?;
+    }
+
+    for port in &simulator.netlist.outputs {
+        let value = simulator.get(&port.name)?;
+        let width = port.nets.len();
+        if width == 1 {
+            println!("{} = {}", port.name, value);
+        } else {
+            println!(
+                "{}[{}] = {:0width$b}",
+                port.name,
+                width,
+                value,
+                width = width
+            );
+        }
+    }
+
+    Ok(())
+}
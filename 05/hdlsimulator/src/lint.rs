@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::chip::{self, ChipDef, Library, Location, Part, PinRef};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Location,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", self.location, severity, self.message)
+    }
+}
+
+// An internal pin with the part outputs driving it and the part inputs reading it.
+struct Internal<'a> {
+    // Set by the first driver.
+    width: Option<usize>,
+    drivers: Vec<&'a Location>,
+    // The reference and the number of bits the reading pin expects.
+    reads: Vec<(&'a PinRef, usize)>,
+}
+
+struct Linter<'a> {
+    def: &'a ChipDef,
+    diagnostics: Vec<Diagnostic>,
+    internals: HashMap<&'a str, Internal<'a>>,
+    // Part output driving each bit of the chip's output pins.
+    outputs: HashMap<&'a str, Vec<Option<&'a Location>>>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, severity: Severity, location: &Location, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            location: location.clone(),
+            message,
+        });
+    }
+
+    fn error(&mut self, location: &Location, message: String) {
+        self.report(Severity::Error, location, message);
+    }
+
+    // Checks that `reference` selects bits inside a pin of the given width.
+    fn check_range(&mut self, reference: &PinRef, width: usize) -> bool {
+        if reference.bits(width).end > width {
+            self.error(
+                &reference.location,
+                format!(
+                    "{} is out of range for {}[{}]",
+                    reference, reference.name, width
+                ),
+            );
+            return false;
+        }
+        true
+    }
+
+    fn check_width(
+        &mut self,
+        location: &Location,
+        pin: &PinRef,
+        bits: usize,
+        signal: &PinRef,
+        signal_bits: usize,
+    ) {
+        if bits != signal_bits {
+            self.error(
+                location,
+                format!(
+                    "width mismatch, {} is {} bits but {} is {} bits",
+                    pin, bits, signal, signal_bits
+                ),
+            );
+        }
+    }
+
+    fn lint_part(&mut self, part: &'a Part, child: &ChipDef) {
+        let mut connected: HashMap<&str, Vec<bool>> = HashMap::new();
+        for conn in &part.connections {
+            let pin = match child.pin(&conn.pin.name) {
+                Some(pin) => pin,
+                None => {
+                    self.error(
+                        &conn.pin.location,
+                        format!("chip {} has no pin {}", child.name, conn.pin.name),
+                    );
+                    continue;
+                }
+            };
+            if !self.check_range(&conn.pin, pin.width) {
+                continue;
+            }
+            let bits = conn.pin.bits(pin.width);
+            let signal = &conn.signal;
+            let is_output = child.output(&pin.name).is_some();
+
+            if !is_output {
+                let used = connected
+                    .entry(pin.name.as_str())
+                    .or_insert_with(|| vec![false; pin.width]);
+                if bits.clone().any(|bit| used[bit]) {
+                    self.error(
+                        &conn.pin.location,
+                        format!("input {} is connected more than once", conn.pin),
+                    );
+                }
+                for bit in bits.clone() {
+                    used[bit] = true;
+                }
+            }
+
+            if signal.is_constant() {
+                if is_output {
+                    self.error(
+                        &signal.location,
+                        format!("output {} cannot drive a constant", conn.pin),
+                    );
+                }
+                continue;
+            }
+
+            if let Some(chip_pin) = self.def.pin(&signal.name) {
+                if !self.check_range(signal, chip_pin.width) {
+                    continue;
+                }
+                let signal_bits = signal.bits(chip_pin.width);
+                self.check_width(
+                    &conn.pin.location,
+                    &conn.pin,
+                    bits.len(),
+                    signal,
+                    signal_bits.len(),
+                );
+                if !is_output {
+                    continue;
+                }
+                if self.def.input(&signal.name).is_some() {
+                    self.error(
+                        &signal.location,
+                        format!("output {} cannot drive input pin {}", conn.pin, signal.name),
+                    );
+                    continue;
+                }
+                let driven = self.outputs.get_mut(signal.name.as_str()).unwrap();
+                let twice = signal_bits.clone().any(|bit| driven[bit].is_some());
+                for bit in signal_bits {
+                    driven[bit] = Some(&signal.location);
+                }
+                if twice {
+                    self.error(
+                        &signal.location,
+                        format!("output pin {} is driven more than once", signal),
+                    );
+                }
+            } else if is_output {
+                if signal.range.is_some() {
+                    self.error(
+                        &signal.location,
+                        format!("sub-bus of internal pin {} cannot be driven", signal.name),
+                    );
+                    continue;
+                }
+                let internal = self
+                    .internals
+                    .entry(signal.name.as_str())
+                    .or_insert_with(|| Internal {
+                        width: None,
+                        drivers: Vec::new(),
+                        reads: Vec::new(),
+                    });
+                internal.drivers.push(&signal.location);
+                let width = *internal.width.get_or_insert(bits.len());
+                if width != bits.len() {
+                    self.error(
+                        &signal.location,
+                        format!(
+                            "internal pin {} is {} bits wide here, {} bits elsewhere",
+                            signal.name,
+                            bits.len(),
+                            width
+                        ),
+                    );
+                }
+            } else {
+                self.internals
+                    .entry(signal.name.as_str())
+                    .or_insert_with(|| Internal {
+                        width: None,
+                        drivers: Vec::new(),
+                        reads: Vec::new(),
+                    })
+                    .reads
+                    .push((signal, bits.len()));
+            }
+        }
+
+        for pin in &child.inputs {
+            if !connected.contains_key(pin.name.as_str()) {
+                self.report(
+                    Severity::Warning,
+                    &part.location,
+                    format!("input {} of {} is not connected", pin.name, child.name),
+                );
+            }
+        }
+    }
+
+    fn lint_internals(&mut self) {
+        let mut names: Vec<&str> = self.internals.keys().copied().collect();
+        names.sort();
+        for name in names {
+            let internal = &self.internals[name];
+            let (drivers, reads) = (internal.drivers.clone(), internal.reads.clone());
+            let width = match internal.width {
+                Some(width) => width,
+                None => {
+                    for (signal, _) in &reads {
+                        self.error(
+                            &signal.location,
+                            format!("internal pin {} is never driven", name),
+                        );
+                    }
+                    continue;
+                }
+            };
+
+            for location in &drivers[1..] {
+                self.error(
+                    location,
+                    format!("internal pin {} is driven more than once", name),
+                );
+            }
+            if reads.is_empty() {
+                self.report(
+                    Severity::Warning,
+                    drivers[0],
+                    format!("internal pin {} is never read", name),
+                );
+            }
+            for (signal, bits) in reads {
+                if self.check_range(signal, width) {
+                    let signal_bits = signal.bits(width).len();
+                    if signal_bits != bits {
+                        self.error(
+                            &signal.location,
+                            format!(
+                                "width mismatch, {} bits are read from {} which is {} bits",
+                                bits, signal, signal_bits
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Checks a chip definition without elaborating it, reporting every problem found.
+pub fn lint_chip(library: &Library, def: &ChipDef) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        def,
+        diagnostics: Vec::new(),
+        internals: HashMap::new(),
+        outputs: def
+            .outputs
+            .iter()
+            .map(|pin| (pin.name.as_str(), vec![None; pin.width]))
+            .collect(),
+    };
+
+    for part in &def.parts {
+        match library.load(&part.name) {
+            Ok(child) => linter.lint_part(part, &child),
+            Err(err) => linter.error(&part.location, err.to_string()),
+        }
+    }
+    linter.lint_internals();
+
+    if def.builtin.is_none() && !def.is_primitive() {
+        for pin in &def.outputs {
+            if linter.outputs[pin.name.as_str()]
+                .iter()
+                .all(Option::is_none)
+            {
+                linter.report(
+                    Severity::Warning,
+                    &pin.location,
+                    format!("output pin {} is never driven", pin.name),
+                );
+            }
+        }
+    }
+
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.location.line, diagnostic.location.column));
+    diagnostics
+}
+
+pub fn lint_file(library: &Library, path: &Path) -> Result<Vec<Diagnostic>, Box<dyn Error>> {
+    Ok(lint_chip(library, &chip::load_file(path)?))
+}
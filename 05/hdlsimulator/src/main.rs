@@ -0,0 +1,21 @@
+use hdlsimulator::Config;
+use std::{env, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = Config::build(&args).unwrap_or_else(|err| {
+        println!("Problem parsing arguments: {err}");
+        println!("Program format: <input hdl path> [--lib dir]... [--builtin] [--cycles N] [--report] [--verilog out.v]");
+        println!("                [--dot out.dot [--expand N]] [--vcd out.vcd [--internal]] [pin=value]...");
+        println!("                --test <tst path or directory> [--lib dir]... [--vcd out.vcd [--internal]]");
+        println!("                --lint <hdl path or directory> [--lib dir]...");
+        println!("                <computer hdl path> --hack program.hack [--lib dir]... [--builtin] [--cycles N] [RAM[n]=value]...");
+        println!("                <input hdl path> --equiv <other hdl path> [--lib dir]... [--samples N]");
+        process::exit(1);
+    });
+
+    if let Err(e) = hdlsimulator::run(config) {
+        println!("Application error: {e}");
+        process::exit(1);
+    }
+}
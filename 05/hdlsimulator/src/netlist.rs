@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::builtin::Builtin;
+use crate::chip::{ChipDef, Library, PinDecl};
+
+// Index of a one bit wire in the flattened chip.
+pub type Net = usize;
+
+pub const FALSE: Net = 0;
+pub const TRUE: Net = 1;
+
+#[derive(Clone, Debug)]
+pub enum Gate {
+    Nand { a: Net, b: Net, out: Net },
+    Dff { input: Net, out: Net, state: usize },
+    Builtin(BuiltinGate),
+}
+
+#[derive(Clone, Debug)]
+pub struct BuiltinGate {
+    pub chip: Builtin,
+    pub name: String,
+    pub inputs: Vec<Port>,
+    pub outputs: Vec<Port>,
+    // Index of the gate's memory in the simulator.
+    pub memory: usize,
+}
+
+impl BuiltinGate {
+    pub fn input(&self, name: &str) -> &[Net] {
+        match self.inputs.iter().find(|port| port.name == name) {
+            Some(port) => &port.nets,
+            None => &[],
+        }
+    }
+}
+
+impl Gate {
+    pub fn inputs(&self) -> Vec<Net> {
+        match self {
+            Gate::Nand { a, b, .. } => vec![*a, *b],
+            Gate::Dff { input, .. } => vec![*input],
+            Gate::Builtin(gate) => gate
+                .inputs
+                .iter()
+                .flat_map(|port| port.nets.clone())
+                .collect(),
+        }
+    }
+
+    // Inputs whose changes reach the outputs without a clock edge in between.
+    pub fn combinational_inputs(&self) -> Vec<Net> {
+        match self {
+            Gate::Nand { a, b, .. } => vec![*a, *b],
+            Gate::Dff { .. } => Vec::new(),
+            Gate::Builtin(gate) => gate
+                .inputs
+                .iter()
+                .filter(|port| gate.chip.is_combinational_input(&port.name))
+                .flat_map(|port| port.nets.clone())
+                .collect(),
+        }
+    }
+
+    pub fn outputs(&self) -> Vec<Net> {
+        match self {
+            Gate::Nand { out, .. } => vec![*out],
+            Gate::Dff { out, .. } => vec![*out],
+            Gate::Builtin(gate) => gate
+                .outputs
+                .iter()
+                .flat_map(|port| port.nets.clone())
+                .collect(),
+        }
+    }
+
+    fn remap(&mut self, map: &dyn Fn(Net) -> Net) {
+        match self {
+            Gate::Nand { a, b, out } => {
+                *a = map(*a);
+                *b = map(*b);
+                *out = map(*out);
+            }
+            Gate::Dff { input, out, .. } => {
+                *input = map(*input);
+                *out = map(*out);
+            }
+            Gate::Builtin(gate) => {
+                for port in gate.inputs.iter_mut().chain(gate.outputs.iter_mut()) {
+                    for net in port.nets.iter_mut() {
+                        *net = map(*net);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Port {
+    pub name: String,
+    pub nets: Vec<Net>,
+}
+
+// A named pin of the chip or of one of its part instances, e.g. scope ["CPU", "ALU_9"].
+#[derive(Clone, Debug)]
+pub struct Signal {
+    pub scope: Vec<String>,
+    pub name: String,
+    pub nets: Vec<Net>,
+}
+
+// A chip flattened down to primitive and built-in gates, sorted so that every gate comes
+// after the gates driving its combinational inputs.
+pub struct Netlist {
+    pub name: String,
+    pub inputs: Vec<Port>,
+    pub outputs: Vec<Port>,
+    pub gates: Vec<Gate>,
+    pub net_count: usize,
+    pub dff_count: usize,
+    pub memory_count: usize,
+    // Pins and internal pins at every level, only kept by `build_with_signals`.
+    pub signals: Vec<Signal>,
+}
+
+impl Netlist {
+    pub fn build(library: &Library, name: &str) -> Result<Netlist, Box<dyn Error>> {
+        Self::elaborate(library, name, false)
+    }
+
+    pub fn build_with_signals(library: &Library, name: &str) -> Result<Netlist, Box<dyn Error>> {
+        Self::elaborate(library, name, true)
+    }
+
+    fn elaborate(library: &Library, name: &str, signals: bool) -> Result<Netlist, Box<dyn Error>> {
+        let def = library.load_top(name)?;
+        let mut elaborator = Elaborator {
+            library,
+            parent: vec![FALSE, TRUE],
+            gates: Vec::new(),
+            stack: Vec::new(),
+            dff_count: 0,
+            memory_count: 0,
+            scope: vec![def.name.clone()],
+            signals: if signals { Some(Vec::new()) } else { None },
+        };
+        let ports = elaborator.instantiate(&def)?;
+        elaborator.resolve(&def, ports)
+    }
+
+    pub fn input(&self, name: &str) -> Option<&Port> {
+        self.inputs.iter().find(|port| port.name == name)
+    }
+
+    pub fn output(&self, name: &str) -> Option<&Port> {
+        self.outputs.iter().find(|port| port.name == name)
+    }
+}
+
+struct Elaborator<'a> {
+    library: &'a Library,
+    // Union-find over nets, connections merge the nets on both sides.
+    parent: Vec<Net>,
+    gates: Vec<Gate>,
+    stack: Vec<String>,
+    dff_count: usize,
+    memory_count: usize,
+    scope: Vec<String>,
+    signals: Option<Vec<Signal>>,
+}
+
+impl Elaborator<'_> {
+    fn new_net(&mut self) -> Net {
+        self.parent.push(self.parent.len());
+        self.parent.len() - 1
+    }
+
+    fn new_nets(&mut self, width: usize) -> Vec<Net> {
+        (0..width).map(|_| self.new_net()).collect()
+    }
+
+    fn find(&mut self, net: Net) -> Net {
+        let mut root = net;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut net = net;
+        while self.parent[net] != root {
+            let next = self.parent[net];
+            self.parent[net] = root;
+            net = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: Net, b: Net) {
+        let a = self.find(a);
+        let b = self.find(b);
+        if a != b {
+            // Keep the constants as roots so they are easy to recognize.
+            if b <= TRUE {
+                self.parent[a] = b;
+            } else {
+                self.parent[b] = a;
+            }
+        }
+    }
+
+    // Creates nets for the chip's pins and everything inside it, returning the pin nets.
+    fn instantiate(&mut self, def: &ChipDef) -> Result<HashMap<String, Vec<Net>>, Box<dyn Error>> {
+        let mut signals: HashMap<String, Vec<Net>> = HashMap::new();
+        for pin in def.inputs.iter().chain(def.outputs.iter()) {
+            let nets = self.new_nets(pin.width);
+            signals.insert(pin.name.clone(), nets);
+        }
+        let ports = signals.clone();
+
+        if def.is_primitive() {
+            self.gates.push(Gate::Nand {
+                a: signals["a"][0],
+                b: signals["b"][0],
+                out: signals["out"][0],
+            });
+            return Ok(ports);
+        }
+        if let Some(builtin) = &def.builtin {
+            let chip = Builtin::from_name(builtin)
+                .ok_or_else(|| format!("{}: unknown built-in chip {}", def.location, builtin))?;
+            self.instantiate_builtin(def, chip, &ports);
+            if chip != Builtin::Dff {
+                self.record(def, &ports);
+            }
+            return Ok(ports);
+        }
+
+        self.stack.push(def.name.clone());
+
+        // Internal pins are defined by the part outputs driving them, which may come later.
+        let mut children = Vec::new();
+        for part in &def.parts {
+            let child = self
+                .library
+                .load(&part.name)
+                .map_err(|err| format!("{}: {}", part.location, err))?;
+            if self.stack.contains(&child.name) {
+                return Err(
+                    format!("{}: chip {} contains itself", part.location, child.name).into(),
+                );
+            }
+
+            for conn in &part.connections {
+                let pin = child.pin(&conn.pin.name).ok_or_else(|| {
+                    format!(
+                        "{}: chip {} has no pin {}",
+                        conn.pin.location, child.name, conn.pin.name
+                    )
+                })?;
+                let bits = conn.pin.bits(pin.width);
+                if bits.end > pin.width {
+                    return Err(format!(
+                        "{}: {} is out of range for {}[{}]",
+                        conn.pin.location, conn.pin, pin.name, pin.width
+                    )
+                    .into());
+                }
+
+                let is_output = child.output(&conn.pin.name).is_some();
+                if !is_output || conn.signal.is_constant() || def.pin(&conn.signal.name).is_some() {
+                    continue;
+                }
+                if conn.signal.range.is_some() {
+                    return Err(format!(
+                        "{}: sub-bus of internal pin {} cannot be driven",
+                        conn.signal.location, conn.signal.name
+                    )
+                    .into());
+                }
+                match signals.get(&conn.signal.name) {
+                    Some(nets) if nets.len() != bits.len() => {
+                        return Err(format!(
+                            "{}: internal pin {} is {} bits wide here, {} bits elsewhere",
+                            conn.signal.location,
+                            conn.signal.name,
+                            bits.len(),
+                            nets.len()
+                        )
+                        .into());
+                    }
+                    Some(_) => {}
+                    None => {
+                        let nets = self.new_nets(bits.len());
+                        signals.insert(conn.signal.name.clone(), nets);
+                    }
+                }
+            }
+            children.push(child);
+        }
+
+        self.record(def, &signals);
+        for (idx, (part, child)) in def.parts.iter().zip(children.iter()).enumerate() {
+            self.scope.push(format!("{}_{}", part.name, idx));
+            let child_ports = self.instantiate(child);
+            self.scope.pop();
+            let child_ports = child_ports?;
+            for conn in &part.connections {
+                let pin = child.pin(&conn.pin.name).unwrap();
+                let pin_nets = child_ports[&pin.name][conn.pin.bits(pin.width)].to_vec();
+                let is_output = child.output(&pin.name).is_some();
+
+                let signal_nets = if conn.signal.is_constant() {
+                    if is_output {
+                        return Err(format!(
+                            "{}: output {} cannot drive a constant",
+                            conn.signal.location, conn.pin
+                        )
+                        .into());
+                    }
+                    let value = if conn.signal.name == "true" {
+                        TRUE
+                    } else {
+                        FALSE
+                    };
+                    vec![value; pin_nets.len()]
+                } else {
+                    if is_output && def.input(&conn.signal.name).is_some() {
+                        return Err(format!(
+                            "{}: output {} cannot drive input pin {}",
+                            conn.signal.location, conn.pin, conn.signal.name
+                        )
+                        .into());
+                    }
+                    let nets = signals.get(&conn.signal.name).ok_or_else(|| {
+                        format!(
+                            "{}: internal pin {} is never driven",
+                            conn.signal.location, conn.signal.name
+                        )
+                    })?;
+                    let bits = conn.signal.bits(nets.len());
+                    if bits.end > nets.len() {
+                        return Err(format!(
+                            "{}: {} is out of range for {}[{}]",
+                            conn.signal.location,
+                            conn.signal,
+                            conn.signal.name,
+                            nets.len()
+                        )
+                        .into());
+                    }
+                    nets[bits].to_vec()
+                };
+
+                if signal_nets.len() != pin_nets.len() {
+                    return Err(format!(
+                        "{}: width mismatch, {} is {} bits but {} is {} bits",
+                        conn.pin.location,
+                        conn.pin,
+                        pin_nets.len(),
+                        conn.signal,
+                        signal_nets.len()
+                    )
+                    .into());
+                }
+                for (pin_net, signal_net) in pin_nets.iter().zip(signal_nets.iter()) {
+                    self.union(*pin_net, *signal_net);
+                }
+            }
+        }
+
+        self.stack.pop();
+        Ok(ports)
+    }
+
+    // Keeps the chip's pins, then its internal pins by name, under the current scope.
+    fn record(&mut self, def: &ChipDef, signals: &HashMap<String, Vec<Net>>) {
+        let recorded = match &mut self.signals {
+            Some(recorded) => recorded,
+            None => return,
+        };
+        let mut names: Vec<&String> = def
+            .inputs
+            .iter()
+            .chain(def.outputs.iter())
+            .map(|pin| &pin.name)
+            .collect();
+        let mut internals: Vec<&String> = signals
+            .keys()
+            .filter(|name| def.pin(name).is_none())
+            .collect();
+        internals.sort();
+        names.extend(internals);
+        for name in names {
+            recorded.push(Signal {
+                scope: self.scope.clone(),
+                name: name.clone(),
+                nets: signals[name].clone(),
+            });
+        }
+    }
+
+    fn instantiate_builtin(
+        &mut self,
+        def: &ChipDef,
+        chip: Builtin,
+        ports: &HashMap<String, Vec<Net>>,
+    ) {
+        if chip == Builtin::Dff {
+            self.gates.push(Gate::Dff {
+                input: ports["in"][0],
+                out: ports["out"][0],
+                state: self.dff_count,
+            });
+            self.dff_count += 1;
+            return;
+        }
+
+        let port = |pin: &PinDecl| Port {
+            name: pin.name.clone(),
+            nets: ports[&pin.name].clone(),
+        };
+        self.gates.push(Gate::Builtin(BuiltinGate {
+            chip,
+            name: def.name.clone(),
+            inputs: def.inputs.iter().map(port).collect(),
+            outputs: def.outputs.iter().map(port).collect(),
+            memory: self.memory_count,
+        }));
+        self.memory_count += 1;
+    }
+
+    // Numbers the merged nets, ties undriven ones to false and sorts the gates.
+    fn resolve(
+        mut self,
+        def: &ChipDef,
+        ports: HashMap<String, Vec<Net>>,
+    ) -> Result<Netlist, Box<dyn Error>> {
+        if self.find(FALSE) == self.find(TRUE) {
+            return Err(format!("{}: true and false are connected", def.name).into());
+        }
+
+        let roots: Vec<Net> = (0..self.parent.len()).map(|net| self.find(net)).collect();
+        let mut drivers: Vec<usize> = vec![0; roots.len()];
+        let driven_nets = self
+            .gates
+            .iter()
+            .flat_map(|gate| gate.outputs())
+            .chain(def.inputs.iter().flat_map(|pin| ports[&pin.name].clone()));
+        for net in driven_nets {
+            let root = roots[net];
+            if root <= TRUE {
+                return Err(format!("{}: a driven signal is tied to a constant", def.name).into());
+            }
+            drivers[root] += 1;
+        }
+        if drivers.iter().any(|count| *count > 1) {
+            return Err(format!("{}: a signal has more than one driver", def.name).into());
+        }
+
+        // Roots are numbered in order, undriven nets read as false.
+        let mut index: Vec<Net> = vec![FALSE; roots.len()];
+        index[TRUE] = TRUE;
+        let mut net_count = 2;
+        for net in 2..roots.len() {
+            if roots[net] == net && drivers[net] > 0 {
+                index[net] = net_count;
+                net_count += 1;
+            }
+        }
+        let map = |net: Net| index[roots[net]];
+
+        let mut gates = self.gates;
+        for gate in gates.iter_mut() {
+            gate.remap(&map);
+        }
+        let port = |name: &String| Port {
+            name: name.clone(),
+            nets: ports[name].iter().map(|net| map(*net)).collect(),
+        };
+        let inputs = def.inputs.iter().map(|pin| port(&pin.name)).collect();
+        let outputs = def.outputs.iter().map(|pin| port(&pin.name)).collect();
+        let mut signals = self.signals.take().unwrap_or_default();
+        for signal in signals.iter_mut() {
+            for net in signal.nets.iter_mut() {
+                *net = map(*net);
+            }
+        }
+
+        Ok(Netlist {
+            name: def.name.clone(),
+            inputs,
+            outputs,
+            gates: sort_gates(gates, net_count, &def.name)?,
+            net_count,
+            dff_count: self.dff_count,
+            memory_count: self.memory_count,
+            signals,
+        })
+    }
+}
+
+// Kahn's algorithm over the gate graph, a leftover gate means a combinational loop.
+// Clocked inputs do not count as edges, so loops through a DFF or register are fine.
+fn sort_gates(gates: Vec<Gate>, net_count: usize, name: &str) -> Result<Vec<Gate>, Box<dyn Error>> {
+    let mut driver: Vec<Option<usize>> = vec![None; net_count];
+    for (idx, gate) in gates.iter().enumerate() {
+        for net in gate.outputs() {
+            driver[net] = Some(idx);
+        }
+    }
+
+    let mut pending: Vec<usize> = vec![0; gates.len()];
+    let mut readers: Vec<Vec<usize>> = vec![Vec::new(); gates.len()];
+    for (idx, gate) in gates.iter().enumerate() {
+        for net in gate.combinational_inputs() {
+            if let Some(source) = driver[net] {
+                pending[idx] += 1;
+                readers[source].push(idx);
+            }
+        }
+    }
+
+    let mut ready: Vec<usize> = (0..gates.len()).filter(|idx| pending[*idx] == 0).collect();
+    let mut order = Vec::with_capacity(gates.len());
+    while let Some(idx) = ready.pop() {
+        order.push(idx);
+        for reader in &readers[idx] {
+            pending[*reader] -= 1;
+            if pending[*reader] == 0 {
+                ready.push(*reader);
+            }
+        }
+    }
+
+    if order.len() != gates.len() {
+        return Err(format!(
+            "{}: combinational loop through {} gates",
+            name,
+            gates.len() - order.len()
+        )
+        .into());
+    }
+    Ok(order.into_iter().map(|idx| gates[idx].clone()).collect())
+}
@@ -0,0 +1,247 @@
+use std::error::Error;
+
+use crate::chip::{ChipDef, Connection, Location, Part, PinDecl, PinRef};
+use crate::tokenizer::{self, Token, TokenType};
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    idx: usize,
+    file: &'a str,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.idx]
+    }
+
+    fn location(&self) -> Location {
+        let token = self.peek();
+        Location {
+            file: String::from(self.file),
+            line: token.line,
+            column: token.column,
+        }
+    }
+
+    fn error(&self, expected: &str) -> Box<dyn Error> {
+        let token = self.peek();
+        let found = match token.token_type {
+            TokenType::Eof => String::from("end of file"),
+            _ => format!("'{}'", token.text),
+        };
+        format!(
+            "{}: expected {}, found {}",
+            self.location(),
+            expected,
+            found
+        )
+        .into()
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.idx].clone();
+        if token.token_type != TokenType::Eof {
+            self.idx += 1;
+        }
+        token
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        let token = self.peek();
+        token.token_type == TokenType::Symbol && token.text == symbol
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), Box<dyn Error>> {
+        if !self.is_symbol(symbol) {
+            return Err(self.error(&format!("'{}'", symbol)));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Box<dyn Error>> {
+        let token = self.peek();
+        if token.token_type != TokenType::Identifier || token.text != keyword {
+            return Err(self.error(keyword));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn expect_identifier(&mut self, what: &str) -> Result<String, Box<dyn Error>> {
+        if self.peek().token_type != TokenType::Identifier {
+            return Err(self.error(what));
+        }
+        Ok(self.advance().text)
+    }
+
+    fn expect_number(&mut self) -> Result<usize, Box<dyn Error>> {
+        if self.peek().token_type != TokenType::Number {
+            return Err(self.error("a number"));
+        }
+        let location = self.location();
+        let text = self.advance().text;
+        text.parse::<usize>()
+            .map_err(|_| format!("{}: number {} is too large", location, text).into())
+    }
+
+    fn parse_chip(&mut self) -> Result<ChipDef, Box<dyn Error>> {
+        let location = self.location();
+        self.expect_keyword("CHIP")?;
+        let name = self.expect_identifier("a chip name")?;
+        self.expect_symbol("{")?;
+
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        loop {
+            let token = self.peek();
+            if token.token_type == TokenType::Identifier && token.text == "IN" {
+                self.advance();
+                inputs.extend(self.parse_pin_decls()?);
+            } else if token.token_type == TokenType::Identifier && token.text == "OUT" {
+                self.advance();
+                outputs.extend(self.parse_pin_decls()?);
+            } else {
+                break;
+            }
+        }
+
+        let mut parts = Vec::new();
+        let mut builtin = None;
+        let token = self.peek();
+        if token.token_type == TokenType::Identifier && token.text == "BUILTIN" {
+            self.advance();
+            builtin = Some(self.expect_identifier("a built-in chip name")?);
+            self.expect_symbol(";")?;
+            let token = self.peek();
+            if token.token_type == TokenType::Identifier && token.text == "CLOCKED" {
+                self.advance();
+                while !self.is_symbol(";") {
+                    self.expect_identifier("a pin name")?;
+                    if !self.is_symbol(";") {
+                        self.expect_symbol(",")?;
+                    }
+                }
+                self.advance();
+            }
+        } else {
+            self.expect_keyword("PARTS")?;
+            self.expect_symbol(":")?;
+            while !self.is_symbol("}") {
+                parts.push(self.parse_part()?);
+            }
+        }
+        self.expect_symbol("}")?;
+
+        if self.peek().token_type != TokenType::Eof {
+            return Err(self.error("end of file"));
+        }
+
+        Ok(ChipDef {
+            name,
+            inputs,
+            outputs,
+            parts,
+            builtin,
+            location,
+        })
+    }
+
+    fn parse_pin_decls(&mut self) -> Result<Vec<PinDecl>, Box<dyn Error>> {
+        let mut pins = Vec::new();
+        loop {
+            let location = self.location();
+            let name = self.expect_identifier("a pin name")?;
+            let mut width = 1;
+            if self.is_symbol("[") {
+                self.advance();
+                width = self.expect_number()?;
+                self.expect_symbol("]")?;
+                if width == 0 || width > 64 {
+                    return Err(format!(
+                        "{}: pin {} has unsupported width {}",
+                        location, name, width
+                    )
+                    .into());
+                }
+            }
+            pins.push(PinDecl {
+                name,
+                width,
+                location,
+            });
+
+            if self.is_symbol(";") {
+                self.advance();
+                return Ok(pins);
+            }
+            self.expect_symbol(",")?;
+        }
+    }
+
+    fn parse_part(&mut self) -> Result<Part, Box<dyn Error>> {
+        let location = self.location();
+        let name = self.expect_identifier("a part name")?;
+        self.expect_symbol("(")?;
+
+        let mut connections = Vec::new();
+        if !self.is_symbol(")") {
+            loop {
+                let pin = self.parse_pin_ref()?;
+                self.expect_symbol("=")?;
+                let signal = self.parse_pin_ref()?;
+                connections.push(Connection { pin, signal });
+
+                if self.is_symbol(")") {
+                    break;
+                }
+                self.expect_symbol(",")?;
+            }
+        }
+        self.expect_symbol(")")?;
+        self.expect_symbol(";")?;
+
+        Ok(Part {
+            name,
+            connections,
+            location,
+        })
+    }
+
+    fn parse_pin_ref(&mut self) -> Result<PinRef, Box<dyn Error>> {
+        let location = self.location();
+        let name = self.expect_identifier("a pin name")?;
+        let mut range = None;
+        if self.is_symbol("[") {
+            self.advance();
+            let from = self.expect_number()?;
+            let mut to = from;
+            if self.is_symbol("..") {
+                self.advance();
+                to = self.expect_number()?;
+            }
+            self.expect_symbol("]")?;
+            if to < from {
+                return Err(
+                    format!("{}: invalid sub-bus {}[{}..{}]", location, name, from, to).into(),
+                );
+            }
+            range = Some((from, to));
+        }
+        Ok(PinRef {
+            name,
+            range,
+            location,
+        })
+    }
+}
+
+pub fn parse(source: &str, file: &str) -> Result<ChipDef, Box<dyn Error>> {
+    let tokens = tokenizer::tokenize(source).map_err(|err| format!("{}:{}", file, err))?;
+    let mut parser = Parser {
+        tokens,
+        idx: 0,
+        file,
+    };
+    parser.parse_chip()
+}
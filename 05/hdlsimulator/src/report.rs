@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::builtin::Builtin;
+use crate::chip::{ChipDef, Library};
+use crate::netlist::{Gate, Netlist};
+
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct GateCount {
+    pub nands: usize,
+    pub dffs: usize,
+    // Built-in memories, registers and devices other than DFF.
+    pub builtins: usize,
+}
+
+impl GateCount {
+    fn add(&mut self, other: GateCount, times: usize) {
+        self.nands += other.nands * times;
+        self.dffs += other.dffs * times;
+        self.builtins += other.builtins * times;
+    }
+}
+
+impl fmt::Display for GateCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} Nand", self.nands)?;
+        if self.dffs > 0 {
+            write!(f, ", {} DFF", self.dffs)?;
+        }
+        if self.builtins > 0 {
+            write!(f, ", {} built-in", self.builtins)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct PartCount {
+    pub name: String,
+    pub instances: usize,
+    pub count: GateCount,
+}
+
+pub struct Report {
+    pub name: String,
+    pub total: GateCount,
+    // Gates per part chip of the top level, in order of first use.
+    pub parts: Vec<PartCount>,
+    // Nand gates on the longest path between inputs or state and outputs or state.
+    pub depth: usize,
+    pub depth_end: String,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.name, self.total)?;
+        let width = self.parts.iter().map(|part| part.name.len()).max();
+        for part in &self.parts {
+            let mut per_part = GateCount::default();
+            per_part.add(part.count, part.instances);
+            writeln!(
+                f,
+                "  {:width$}  x{:<3} {}",
+                part.name,
+                part.instances,
+                per_part,
+                width = width.unwrap_or(0)
+            )?;
+        }
+        write!(
+            f,
+            "critical path: {} Nand levels, ending at {}",
+            self.depth, self.depth_end
+        )
+    }
+}
+
+// Counts the primitives of a chip definition, memoized per chip name.
+fn count(
+    library: &Library,
+    def: &ChipDef,
+    counts: &mut HashMap<String, GateCount>,
+) -> Result<GateCount, Box<dyn Error>> {
+    if let Some(count) = counts.get(&def.name) {
+        return Ok(*count);
+    }
+    let mut total = GateCount::default();
+    if def.is_primitive() {
+        total.nands = 1;
+    } else if let Some(builtin) = &def.builtin {
+        match Builtin::from_name(builtin) {
+            Some(Builtin::Dff) => total.dffs = 1,
+            _ => total.builtins = 1,
+        }
+    } else {
+        for part in &def.parts {
+            let child = library.load(&part.name)?;
+            total.add(count(library, &child, counts)?, 1);
+        }
+    }
+    counts.insert(def.name.clone(), total);
+    Ok(total)
+}
+
+// Longest run of Nand gates through the combinational logic of a flattened chip.
+fn critical_path(netlist: &Netlist) -> (usize, String) {
+    let mut depth = vec![0; netlist.net_count];
+    let mut longest = (0, String::from("no gates"));
+    let mut end = |level: usize, name: &str| {
+        if level > longest.0 {
+            longest = (level, String::from(name));
+        }
+    };
+
+    for gate in &netlist.gates {
+        match gate {
+            Gate::Nand { a, b, out } => depth[*out] = depth[*a].max(depth[*b]) + 1,
+            Gate::Dff { .. } => {}
+            Gate::Builtin(_) => {
+                let through = gate
+                    .combinational_inputs()
+                    .iter()
+                    .map(|net| depth[*net])
+                    .max()
+                    .unwrap_or(0);
+                for net in gate.outputs() {
+                    depth[net] = through;
+                }
+            }
+        }
+    }
+
+    // Clocked inputs come before their drivers in the gate order, so check them last.
+    for gate in &netlist.gates {
+        match gate {
+            Gate::Nand { .. } => {}
+            Gate::Dff { input, .. } => end(depth[*input], "a DFF input"),
+            Gate::Builtin(builtin) => {
+                for net in gate.inputs() {
+                    end(depth[net], &format!("{} input", builtin.name));
+                }
+            }
+        }
+    }
+    for port in &netlist.outputs {
+        for net in &port.nets {
+            end(depth[*net], &port.name);
+        }
+    }
+    longest
+}
+
+pub fn build(library: &Library, netlist: &Netlist) -> Result<Report, Box<dyn Error>> {
+    let def = library.load_top(&netlist.name)?;
+    let mut counts = HashMap::new();
+    let mut total = GateCount::default();
+    let mut parts: Vec<PartCount> = Vec::new();
+    for part in &def.parts {
+        let child = library.load(&part.name)?;
+        let part_count = count(library, &child, &mut counts)?;
+        total.add(part_count, 1);
+        match parts.iter_mut().find(|entry| entry.name == part.name) {
+            Some(entry) => entry.instances += 1,
+            None => parts.push(PartCount {
+                name: part.name.clone(),
+                instances: 1,
+                count: part_count,
+            }),
+        }
+    }
+    if def.parts.is_empty() {
+        total = count(library, &def, &mut counts)?;
+    }
+
+    let (depth, depth_end) = critical_path(netlist);
+    Ok(Report {
+        name: def.name.clone(),
+        total,
+        parts,
+        depth,
+        depth_end,
+    })
+}
@@ -0,0 +1,658 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::chip::Library;
+use crate::netlist::Netlist;
+use crate::parse_value;
+use crate::simulator::Simulator;
+use crate::vcd::{VcdConfig, VcdWriter};
+
+// Upper bound for `while` loops, so a broken chip fails instead of hanging.
+const MAX_ITERATIONS: usize = 1_000_000;
+// Iterations of a `repeat` without a count before the script fails, or one per line of the
+// compare file if it has more. MAX_ITERATIONS of a CPU sized chip would take hours.
+const ENDLESS_REPEATS: usize = 1000;
+
+#[derive(Debug)]
+enum Command {
+    Load(String),
+    OutputFile,
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Reference, String),
+    Eval,
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    Echo,
+    Repeat(Option<usize>, Vec<Statement>),
+    While(Condition, Vec<Statement>),
+    LoadMemory(String, String),
+}
+
+#[derive(Debug)]
+struct Statement {
+    command: Command,
+    line: usize,
+}
+
+// A chip pin, or a word of a built-in part such as `RAM16K[2]` or `DRegister[]`.
+#[derive(Clone, Debug)]
+struct Reference {
+    name: String,
+    index: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+struct Column {
+    text: String,
+    reference: Reference,
+    format: char,
+    left: usize,
+    len: usize,
+    right: usize,
+}
+
+#[derive(Debug)]
+struct Condition {
+    reference: Reference,
+    op: String,
+    value: String,
+}
+
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+struct Word {
+    text: String,
+    line: usize,
+}
+
+// Splits a script into words, quoted strings and the `,;{}` separators.
+fn split_words(source: &str) -> Result<Vec<Word>, Box<dyn Error>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut words = Vec::new();
+    let mut idx = 0;
+    let mut line = 1;
+
+    while idx < chars.len() {
+        let c = chars[idx];
+        let next = chars.get(idx + 1).copied();
+        if c == '\n' {
+            line += 1;
+            idx += 1;
+        } else if c.is_whitespace() {
+            idx += 1;
+        } else if c == '/' && next == Some('/') {
+            while idx < chars.len() && chars[idx] != '\n' {
+                idx += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            let start = line;
+            idx += 2;
+            while idx + 1 < chars.len() && !(chars[idx] == '*' && chars[idx + 1] == '/') {
+                if chars[idx] == '\n' {
+                    line += 1;
+                }
+                idx += 1;
+            }
+            if idx + 1 >= chars.len() {
+                return Err(format!("{}: unterminated comment", start).into());
+            }
+            idx += 2;
+        } else if c == '"' {
+            let start = line;
+            let mut text = String::new();
+            idx += 1;
+            while idx < chars.len() && chars[idx] != '"' {
+                if chars[idx] == '\n' {
+                    line += 1;
+                }
+                text.push(chars[idx]);
+                idx += 1;
+            }
+            if idx >= chars.len() {
+                return Err(format!("{}: unterminated string", start).into());
+            }
+            idx += 1;
+            words.push(Word { text, line: start });
+        } else if ",;{}".contains(c) {
+            words.push(Word {
+                text: c.to_string(),
+                line,
+            });
+            idx += 1;
+        } else {
+            let mut text = String::new();
+            let ends_word = |idx: usize| {
+                idx >= chars.len()
+                    || chars[idx].is_whitespace()
+                    || ",;{}".contains(chars[idx])
+                    || chars[idx] == '/' && chars.get(idx + 1) == Some(&'/')
+            };
+            while !ends_word(idx) {
+                text.push(chars[idx]);
+                idx += 1;
+            }
+            words.push(Word { text, line });
+        }
+    }
+    Ok(words)
+}
+
+struct Parser {
+    words: Vec<Word>,
+    idx: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.words.get(self.idx).map(|word| word.text.as_str())
+    }
+
+    fn line(&self) -> usize {
+        match self.words.get(self.idx).or(self.words.last()) {
+            Some(word) => word.line,
+            None => 1,
+        }
+    }
+
+    fn parse_block(&mut self, nested: bool) -> Result<Vec<Statement>, Box<dyn Error>> {
+        let mut statements = Vec::new();
+        loop {
+            match self.peek() {
+                None if nested => return Err(format!("{}: expected '}}'", self.line()).into()),
+                None => return Ok(statements),
+                Some("}") if nested => {
+                    self.idx += 1;
+                    return Ok(statements);
+                }
+                Some("}") => return Err(format!("{}: unexpected '}}'", self.line()).into()),
+                Some(",") | Some(";") => self.idx += 1,
+                Some(_) => statements.push(self.parse_statement()?),
+            }
+        }
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, Box<dyn Error>> {
+        let line = self.line();
+        let mut words = Vec::new();
+        while let Some(text) = self.peek() {
+            if ",;{}".contains(text) && text.len() == 1 {
+                break;
+            }
+            words.push(String::from(text));
+            self.idx += 1;
+        }
+
+        let error = |message: &str| format!("{}: {}", line, message);
+        let arg = |idx: usize| {
+            words
+                .get(idx)
+                .cloned()
+                .ok_or_else(|| error(&format!("{} expects an argument", words[0])))
+        };
+        let command = match words[0].as_str() {
+            "load" => Command::Load(arg(1)?),
+            "output-file" => Command::OutputFile,
+            "compare-to" => Command::CompareTo(arg(1)?),
+            "output-list" => Command::OutputList(
+                words[1..]
+                    .iter()
+                    .map(|text| parse_column(text).map_err(|err| error(&err)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            "set" => Command::Set(parse_reference(&arg(1)?), arg(2)?),
+            "eval" => Command::Eval,
+            "tick" => Command::Tick,
+            "tock" => Command::Tock,
+            "ticktock" => Command::TickTock,
+            "output" => Command::Output,
+            "echo" | "clear-echo" => Command::Echo,
+            "repeat" | "while" => {
+                let is_repeat = words[0] == "repeat";
+                if self.peek() != Some("{") {
+                    return Err(error("expected '{'").into());
+                }
+                self.idx += 1;
+                let body = self.parse_block(true)?;
+                if is_repeat {
+                    let count = match words.get(1) {
+                        Some(count) => Some(
+                            count
+                                .parse::<usize>()
+                                .map_err(|_| error("repeat expects a count"))?,
+                        ),
+                        None => None,
+                    };
+                    Command::Repeat(count, body)
+                } else {
+                    let condition = Condition {
+                        reference: parse_reference(&arg(1)?),
+                        op: arg(2)?,
+                        value: arg(3)?,
+                    };
+                    Command::While(condition, body)
+                }
+            }
+            chip if words.get(1).map(String::as_str) == Some("load") => {
+                Command::LoadMemory(String::from(chip), arg(2)?)
+            }
+            other => return Err(error(&format!("unknown command '{}'", other)).into()),
+        };
+        Ok(Statement { command, line })
+    }
+}
+
+fn parse_reference(text: &str) -> Reference {
+    match text.strip_suffix(']').and_then(|text| text.split_once('[')) {
+        Some((name, index)) => Reference {
+            name: String::from(name),
+            index: index.parse::<usize>().ok(),
+        },
+        None => Reference {
+            name: String::from(text),
+            index: None,
+        },
+    }
+}
+
+// Parses an output-list entry such as `out%B1.16.1`: format, left padding, length, right padding.
+fn parse_column(text: &str) -> Result<Column, String> {
+    let invalid = || format!("invalid output column '{}'", text);
+    let (name, format) = text.split_once('%').ok_or_else(invalid)?;
+    let mut chars = format.chars();
+    let format_char = chars.next().ok_or_else(invalid)?;
+    if !"BXDS".contains(format_char) {
+        return Err(invalid());
+    }
+    let sizes: Vec<usize> = chars
+        .as_str()
+        .split('.')
+        .map(|size| size.parse::<usize>().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    if sizes.len() != 3 {
+        return Err(invalid());
+    }
+    Ok(Column {
+        text: String::from(name),
+        reference: parse_reference(name),
+        format: format_char,
+        left: sizes[0],
+        len: sizes[1],
+        right: sizes[2],
+    })
+}
+
+// Runs a test script the way the Hardware Simulator does, comparing each output line
+// with the compare file as it is produced.
+struct Runner<'a> {
+    dir: PathBuf,
+    lib_dirs: &'a [PathBuf],
+    simulator: Option<Simulator>,
+    columns: Vec<Column>,
+    expected: Vec<String>,
+    lines: usize,
+    time: u64,
+    ticked: bool,
+    vcd_config: Option<&'a VcdConfig>,
+    vcd: Option<VcdWriter>,
+}
+
+impl Runner<'_> {
+    fn simulator(&mut self) -> Result<&mut Simulator, Box<dyn Error>> {
+        self.simulator
+            .as_mut()
+            .ok_or_else(|| "no chip is loaded".into())
+    }
+
+    fn execute_all(&mut self, statements: &[Statement]) -> Result<(), Box<dyn Error>> {
+        for statement in statements {
+            self.execute(statement)
+                .map_err(|err| match err.to_string() {
+                    message if message.starts_with("line ") => message,
+                    message => format!("line {}: {}", statement.line, message),
+                })?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, statement: &Statement) -> Result<(), Box<dyn Error>> {
+        match &statement.command {
+            Command::Load(file) => {
+                let name = file
+                    .strip_suffix(".hdl")
+                    .ok_or_else(|| format!("{} is not a chip", file))?;
+                let mut search_paths = vec![self.dir.clone()];
+                search_paths.extend(self.lib_dirs.iter().cloned());
+                let library = Library::build(search_paths);
+                let netlist = match self.vcd_config {
+                    Some(config) if config.internals => {
+                        Netlist::build_with_signals(&library, name)?
+                    }
+                    _ => Netlist::build(&library, name)?,
+                };
+                let simulator = Simulator::build(netlist);
+                if let Some(config) = self.vcd_config {
+                    let mut vcd = VcdWriter::create(config, &simulator)?;
+                    vcd.sample(&simulator, false)?;
+                    self.vcd = Some(vcd);
+                }
+                self.simulator = Some(simulator);
+            }
+            Command::OutputFile | Command::Echo => {}
+            Command::CompareTo(file) => {
+                let path = self.dir.join(file);
+                let text = fs::read_to_string(&path)
+                    .map_err(|err| format!("{}: {}", path.display(), err))?;
+                self.expected = text.lines().map(|line| line.trim().to_string()).collect();
+            }
+            Command::OutputList(columns) => {
+                self.columns = columns.clone();
+                let header = self.header();
+                self.compare(header)?;
+            }
+            Command::Set(reference, value) => {
+                let value = parse_value(value)?;
+                let simulator = self.simulator()?;
+                if simulator.width(&reference.name).is_ok() {
+                    simulator.set(&reference.name, value as u64)?;
+                } else {
+                    let memory = simulator
+                        .memory_mut(&reference.name)
+                        .ok_or_else(|| format!("unknown pin or part {}", reference.name))?;
+                    let index = reference.index.unwrap_or(0);
+                    let word = memory
+                        .get_mut(index)
+                        .ok_or_else(|| format!("{}[{}] is out of range", reference.name, index))?;
+                    *word = value as u16;
+                }
+            }
+            Command::Eval => {
+                self.simulator()?.eval();
+                self.sample()?;
+            }
+            Command::Tick => self.tick()?,
+            Command::Tock => self.tock()?,
+            Command::TickTock => {
+                self.tick()?;
+                self.tock()?;
+            }
+            Command::Output => {
+                let line = self.output_line()?;
+                self.compare(line)?;
+            }
+            Command::Repeat(Some(count), body) => {
+                for _ in 0..*count {
+                    self.execute_all(body)?;
+                }
+            }
+            Command::Repeat(None, body) => {
+                let limit = self.expected.len().max(ENDLESS_REPEATS);
+                for _ in 0..limit {
+                    self.execute_all(body)?;
+                }
+                return Err(format!(
+                    "repeat without a count never ends, stopped after {} iterations",
+                    limit
+                )
+                .into());
+            }
+            Command::While(condition, body) => {
+                self.press_awaited_key(condition)?;
+                let mut iterations = 0;
+                while self.holds(condition)? {
+                    if iterations == MAX_ITERATIONS {
+                        return Err("while loop never ends".into());
+                    }
+                    self.execute_all(body)?;
+                    iterations += 1;
+                }
+            }
+            Command::LoadMemory(chip, file) => {
+                let path = self.dir.join(file);
+                let words = read_hack(&path)?;
+                let memory = self
+                    .simulator()?
+                    .memory_mut(chip)
+                    .ok_or_else(|| format!("chip has no {} part", chip))?;
+                if words.len() > memory.len() {
+                    return Err(format!("{} does not fit in {}", file, chip).into());
+                }
+                memory.fill(0);
+                memory[..words.len()].copy_from_slice(&words);
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        self.simulator()?.tick();
+        self.ticked = true;
+        self.sample()
+    }
+
+    fn tock(&mut self) -> Result<(), Box<dyn Error>> {
+        self.simulator()?.tock();
+        self.ticked = false;
+        self.time += 1;
+        self.sample()
+    }
+
+    fn sample(&mut self) -> Result<(), Box<dyn Error>> {
+        if let (Some(vcd), Some(simulator)) = (&mut self.vcd, &self.simulator) {
+            vcd.sample(simulator, self.ticked)?;
+        }
+        Ok(())
+    }
+
+    // Scripts wait for a human to hold down a key, e.g. `while out <> 75 {...}` for 'K'.
+    // Hold down the awaited key on the built-in Keyboard instead.
+    fn press_awaited_key(&mut self, condition: &Condition) -> Result<(), Box<dyn Error>> {
+        if condition.op != "<>" {
+            return Ok(());
+        }
+        let value = parse_value(&condition.value)?;
+        if let Some(keyboard) = self.simulator()?.memory_mut("Keyboard") {
+            keyboard[0] = value as u16;
+        }
+        Ok(())
+    }
+
+    fn holds(&mut self, condition: &Condition) -> Result<bool, Box<dyn Error>> {
+        let value = self.value(&condition.reference)?;
+        let expected = parse_value(&condition.value)?;
+        let holds = match condition.op.as_str() {
+            "=" => value == expected,
+            "<>" => value != expected,
+            "<" => value < expected,
+            ">" => value > expected,
+            "<=" => value <= expected,
+            ">=" => value >= expected,
+            op => return Err(format!("unknown operator '{}'", op).into()),
+        };
+        Ok(holds)
+    }
+
+    // The value of a pin or built-in word, 16-bit values read as signed like in the
+    // Hardware Simulator. Also returns the width.
+    fn read(&self, reference: &Reference) -> Result<(i64, usize), Box<dyn Error>> {
+        let simulator = self.simulator.as_ref().ok_or("no chip is loaded")?;
+        let (value, width) = match simulator.width(&reference.name) {
+            Ok(width) => (simulator.get(&reference.name)?, width),
+            Err(_) => {
+                let memory = simulator
+                    .memory(&reference.name)
+                    .ok_or_else(|| format!("unknown pin or part {}", reference.name))?;
+                let index = reference.index.unwrap_or(0);
+                let word = memory
+                    .get(index)
+                    .ok_or_else(|| format!("{}[{}] is out of range", reference.name, index))?;
+                (*word as u64, 16)
+            }
+        };
+        let value = if width == 16 {
+            value as u16 as i16 as i64
+        } else {
+            value as i64
+        };
+        Ok((value, width))
+    }
+
+    fn value(&self, reference: &Reference) -> Result<i64, Box<dyn Error>> {
+        Ok(self.read(reference)?.0)
+    }
+
+    fn header(&self) -> String {
+        let mut line = String::from("|");
+        for column in &self.columns {
+            let width = column.left + column.len + column.right;
+            let name: String = column.text.chars().take(width).collect();
+            let left = (width - name.chars().count()) / 2;
+            line += &format!(
+                "{:left$}{:<rest$}|",
+                "",
+                name,
+                left = left,
+                rest = width - left
+            );
+        }
+        line
+    }
+
+    fn output_line(&self) -> Result<String, Box<dyn Error>> {
+        let mut line = String::from("|");
+        for column in &self.columns {
+            let text = if column.reference.name == "time" && column.format == 'S' {
+                let time = format!("{}{}", self.time, if self.ticked { "+" } else { "" });
+                format!("{:<len$}", time, len = column.len)
+            } else {
+                let (value, width) = self.read(&column.reference)?;
+                let bits = value as u64 & mask(width);
+                let len = column.len;
+                match column.format {
+                    'B' => format!("{:0len$b}", bits & mask(len), len = len),
+                    'X' => format!("{:0len$X}", bits & mask(4 * len), len = len),
+                    'D' => format!("{:>len$}", value, len = len),
+                    _ => format!("{:<len$}", value, len = len),
+                }
+            };
+            line += &format!(
+                "{:left$}{}{:right$}|",
+                "",
+                text,
+                "",
+                left = column.left,
+                right = column.right
+            );
+        }
+        Ok(line)
+    }
+
+    // Compares a produced line with the compare file, `*` matching any character.
+    fn compare(&mut self, line: String) -> Result<(), Box<dyn Error>> {
+        self.lines += 1;
+        let expected = match self.expected.get(self.lines - 1) {
+            Some(expected) => expected,
+            None if self.expected.is_empty() => return Ok(()),
+            None => {
+                return Err(format!("output line {} is not in the compare file", self.lines).into())
+            }
+        };
+        let matches = expected.chars().count() == line.chars().count()
+            && expected
+                .chars()
+                .zip(line.chars())
+                .all(|(want, got)| want == '*' || want == got);
+        if !matches {
+            return Err(format!(
+                "comparison failure at line {}\n  expected: {}\n  actual:   {}",
+                self.lines, expected, line
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+fn mask(width: usize) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+fn read_hack(path: &Path) -> Result<Vec<u16>, Box<dyn Error>> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            u16::from_str_radix(line, 2)
+                .map_err(|_| format!("{}: invalid instruction '{}'", path.display(), line).into())
+        })
+        .collect()
+}
+
+// Runs one .tst file. Chips are looked up in the script's directory, then in `lib_dirs`,
+// then among the built-in chips.
+// With a VCD config the run is also dumped as a waveform, failing or not.
+pub fn run_script(
+    path: &Path,
+    lib_dirs: &[PathBuf],
+    vcd: Option<&VcdConfig>,
+) -> Result<Outcome, Box<dyn Error>> {
+    let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let words = split_words(&source).map_err(|err| format!("{}:{}", path.display(), err))?;
+
+    // CPU and VM emulator scripts load programs instead of chips.
+    let loaded = words
+        .iter()
+        .position(|word| word.text == "load")
+        .and_then(|idx| words.get(idx + 1))
+        .map(|word| word.text.as_str());
+    match loaded {
+        Some(file) if file.ends_with(".hdl") => {}
+        _ => return Ok(Outcome::Skip(String::from("not a hardware test script"))),
+    }
+
+    let mut parser = Parser { words, idx: 0 };
+    let statements = parser
+        .parse_block(false)
+        .map_err(|err| format!("{}:{}", path.display(), err))?;
+
+    let mut runner = Runner {
+        dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        lib_dirs,
+        simulator: None,
+        columns: Vec::new(),
+        expected: Vec::new(),
+        lines: 0,
+        time: 0,
+        ticked: false,
+        vcd_config: vcd,
+        vcd: None,
+    };
+    let result = runner.execute_all(&statements);
+    if let Some(vcd) = runner.vcd.take() {
+        vcd.finish()?;
+    }
+    let outcome = match result {
+        Ok(()) if runner.lines < runner.expected.len() => Outcome::Fail(format!(
+            "produced {} of the {} compare file lines",
+            runner.lines,
+            runner.expected.len()
+        )),
+        Ok(()) => Outcome::Pass,
+        Err(err) => Outcome::Fail(err.to_string()),
+    };
+    Ok(outcome)
+}
@@ -0,0 +1,166 @@
+use std::error::Error;
+
+use crate::builtin::Builtin;
+use crate::netlist::{BuiltinGate, Gate, Net, Netlist, Port, TRUE};
+
+pub struct Simulator {
+    pub netlist: Netlist,
+    values: Vec<bool>,
+    dffs: Vec<bool>,
+    dffs_next: Vec<bool>,
+    // Built-in chip state. Like in the Hardware Simulator it is written on tick, while the
+    // outputs only follow on tock.
+    memories: Vec<Vec<u16>>,
+}
+
+impl Simulator {
+    pub fn build(netlist: Netlist) -> Simulator {
+        let mut values = vec![false; netlist.net_count];
+        values[TRUE] = true;
+        let mut memories = vec![Vec::new(); netlist.memory_count];
+        for gate in &netlist.gates {
+            if let Gate::Builtin(gate) = gate {
+                memories[gate.memory] = vec![0; gate.chip.memory_size()];
+            }
+        }
+        let mut simulator = Simulator {
+            values,
+            dffs: vec![false; netlist.dff_count],
+            dffs_next: vec![false; netlist.dff_count],
+            memories,
+            netlist,
+        };
+        simulator.eval();
+        simulator
+    }
+
+    // Clears the state and pins, as after loading the chip.
+    pub fn reset(&mut self) {
+        self.values.fill(false);
+        self.values[TRUE] = true;
+        self.dffs.fill(false);
+        self.dffs_next.fill(false);
+        for memory in self.memories.iter_mut() {
+            memory.fill(0);
+        }
+        self.eval();
+    }
+
+    fn port(&self, name: &str) -> Result<&Port, Box<dyn Error>> {
+        self.netlist
+            .input(name)
+            .or_else(|| self.netlist.output(name))
+            .ok_or_else(|| format!("chip {} has no pin {}", self.netlist.name, name).into())
+    }
+
+    pub fn width(&self, name: &str) -> Result<usize, Box<dyn Error>> {
+        Ok(self.port(name)?.nets.len())
+    }
+
+    pub fn set(&mut self, name: &str, value: u64) -> Result<(), Box<dyn Error>> {
+        let nets = match self.netlist.input(name) {
+            Some(port) => port.nets.clone(),
+            None => {
+                return Err(format!("chip {} has no input pin {}", self.netlist.name, name).into())
+            }
+        };
+        for (bit, net) in nets.iter().enumerate() {
+            self.values[*net] = value >> bit & 1 == 1;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<u64, Box<dyn Error>> {
+        Ok(self.read_nets(&self.port(name)?.nets))
+    }
+
+    pub fn read_nets(&self, nets: &[Net]) -> u64 {
+        read_bits(&self.values, nets)
+    }
+
+    fn write_nets(values: &mut [bool], nets: &[Net], value: u64) {
+        for (bit, net) in nets.iter().enumerate() {
+            values[*net] = value >> bit & 1 == 1;
+        }
+    }
+
+    // The memory of the first built-in part with the given chip name, e.g. "RAM16K" or
+    // "DRegister". Registers, PC and Keyboard hold a single word.
+    pub fn memory(&self, name: &str) -> Option<&[u16]> {
+        let gate = self.builtin_gate(name)?;
+        Some(&self.memories[gate.memory])
+    }
+
+    pub fn memory_mut(&mut self, name: &str) -> Option<&mut Vec<u16>> {
+        let idx = self.builtin_gate(name)?.memory;
+        Some(&mut self.memories[idx])
+    }
+
+    fn builtin_gate(&self, name: &str) -> Option<&BuiltinGate> {
+        self.netlist.gates.iter().find_map(|gate| match gate {
+            Gate::Builtin(gate) if gate.name == name => Some(gate),
+            _ => None,
+        })
+    }
+
+    // Propagates the current inputs and state through all gates.
+    pub fn eval(&mut self) {
+        for gate in &self.netlist.gates {
+            match gate {
+                Gate::Nand { a, b, out } => {
+                    self.values[*out] = !(self.values[*a] && self.values[*b]);
+                }
+                Gate::Dff { out, state, .. } => {
+                    self.values[*out] = self.dffs[*state];
+                }
+                Gate::Builtin(gate) => {
+                    let memory = &self.memories[gate.memory];
+                    let address = read_bits(&self.values, gate.input("address")) as usize;
+                    let value = memory[address % memory.len()];
+                    Self::write_nets(&mut self.values, &gate.outputs[0].nets, value as u64);
+                }
+            }
+        }
+    }
+
+    // First half of a clock cycle: computes the next state from the current inputs.
+    pub fn tick(&mut self) {
+        self.eval();
+        for gate in &self.netlist.gates {
+            match gate {
+                Gate::Nand { .. } => {}
+                Gate::Dff { input, state, .. } => {
+                    self.dffs_next[*state] = self.values[*input];
+                }
+                Gate::Builtin(gate) => {
+                    let values = &self.values;
+                    let read = |pin: &str| read_bits(values, gate.input(pin));
+                    let memory = &mut self.memories[gate.memory];
+                    let write = match gate.chip {
+                        Builtin::PC if read("reset") == 1 => Some((0, 0)),
+                        Builtin::PC if read("load") == 1 => Some((0, read("in") as u16)),
+                        Builtin::PC if read("inc") == 1 => Some((0, memory[0].wrapping_add(1))),
+                        Builtin::PC => None,
+                        _ if !gate.chip.is_clocked() || read("load") == 0 => None,
+                        _ => Some((read("address") as usize, read("in") as u16)),
+                    };
+                    if let Some((address, value)) = write {
+                        memory[address] = value;
+                    }
+                }
+            }
+        }
+    }
+
+    // Second half of a clock cycle: the outputs take on the state latched by tick.
+    pub fn tock(&mut self) {
+        self.dffs.copy_from_slice(&self.dffs_next);
+        self.eval();
+    }
+}
+
+fn read_bits(values: &[bool], nets: &[Net]) -> u64 {
+    nets.iter()
+        .enumerate()
+        .fold(0, |value, (bit, net)| value | (values[*net] as u64) << bit)
+}
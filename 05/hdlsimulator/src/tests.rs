@@ -0,0 +1,530 @@
+use std::path::PathBuf;
+
+use crate::chip::Library;
+use crate::cosim;
+use crate::dot;
+use crate::equiv::{self, Verdict};
+use crate::lint;
+use crate::netlist::Netlist;
+use crate::parser;
+use crate::report;
+use crate::script::{self, Outcome};
+use crate::simulator::Simulator;
+use crate::vcd::VcdConfig;
+use crate::verilog;
+
+type GateFn = fn(bool, bool) -> bool;
+
+fn project_dir(project: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../..")
+        .join(project)
+}
+
+fn library(projects: &[&str]) -> Library {
+    Library::build(
+        projects
+            .iter()
+            .map(|project| project_dir(project))
+            .collect(),
+    )
+}
+
+fn simulator(name: &str) -> Simulator {
+    let library = library(&["01", "02"]);
+    Simulator::build(Netlist::build(&library, name).unwrap())
+}
+
+#[test]
+fn test_gates_truth_tables() {
+    let gates: [(&str, GateFn); 4] = [
+        ("And", |a, b| a && b),
+        ("Or", |a, b| a || b),
+        ("Xor", |a, b| a != b),
+        ("Nand", |a, b| !(a && b)),
+    ];
+    for (name, function) in gates {
+        let mut sim = simulator(name);
+        for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+            sim.set("a", a as u64).unwrap();
+            sim.set("b", b as u64).unwrap();
+            sim.eval();
+            assert_eq!(sim.get("out").unwrap(), function(a, b) as u64, "{}", name);
+        }
+    }
+}
+
+#[test]
+fn test_sub_buses_and_constants() {
+    let mut sim = simulator("Inc16");
+    sim.set("in", 41).unwrap();
+    sim.eval();
+    assert_eq!(sim.get("out").unwrap(), 42);
+    sim.set("in", 0xFFFF).unwrap();
+    sim.eval();
+    assert_eq!(sim.get("out").unwrap(), 0);
+
+    let mut sim = simulator("DMux4Way");
+    sim.set("in", 1).unwrap();
+    sim.set("sel", 2).unwrap();
+    sim.eval();
+    assert_eq!(sim.get("c").unwrap(), 1);
+    assert_eq!(
+        sim.get("a").unwrap() + sim.get("b").unwrap() + sim.get("d").unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_alu() {
+    let mut sim = simulator("ALU");
+    // x - y: zx=0 nx=1 zy=0 ny=0 f=1 no=1
+    let controls = [
+        ("zx", 0),
+        ("nx", 1),
+        ("zy", 0),
+        ("ny", 0),
+        ("f", 1),
+        ("no", 1),
+    ];
+    for (pin, value) in controls {
+        sim.set(pin, value).unwrap();
+    }
+    sim.set("x", 17).unwrap();
+    sim.set("y", 3).unwrap();
+    sim.eval();
+    assert_eq!(sim.get("out").unwrap(), 14);
+    assert_eq!(sim.get("zr").unwrap(), 0);
+    assert_eq!(sim.get("ng").unwrap(), 0);
+
+    sim.set("x", 3).unwrap();
+    sim.set("y", 17).unwrap();
+    sim.eval();
+    assert_eq!(sim.get("out").unwrap(), (-14i16) as u16 as u64);
+    assert_eq!(sim.get("ng").unwrap(), 1);
+}
+
+#[test]
+fn test_parse_errors_have_locations() {
+    let err = parser::parse(
+        "CHIP Foo {\n    IN a;\n    OUT out\n    PARTS:\n}",
+        "Foo.hdl",
+    )
+    .unwrap_err();
+    assert_eq!(err.to_string(), "Foo.hdl:4:5: expected ',', found 'PARTS'");
+
+    let def = parser::parse(
+        "/** doc */ CHIP Foo { IN a[16]; OUT out; PARTS: Or8Way(in=a[0..7], out=out); }",
+        "Foo.hdl",
+    )
+    .unwrap();
+    assert_eq!(def.inputs[0].width, 16);
+    assert_eq!(def.parts[0].connections[0].signal.range, Some((0, 7)));
+}
+
+#[test]
+fn test_combinational_loop_is_rejected() {
+    let dir = std::env::temp_dir().join("hdlsimulator_loop");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Loop.hdl"),
+        "CHIP Loop { IN a; OUT out; PARTS: Nand(a=a, b=x, out=x, out=out); }",
+    )
+    .unwrap();
+    let library = Library::build(vec![dir]);
+    let err = Netlist::build(&library, "Loop").err().unwrap();
+    assert!(err.to_string().contains("combinational loop"));
+}
+
+#[test]
+fn test_clocked_chips() {
+    let library = library(&["01", "02", "03/a"]);
+    let mut sim = Simulator::build(Netlist::build(&library, "Bit").unwrap());
+    sim.set("in", 1).unwrap();
+    sim.set("load", 1).unwrap();
+    sim.tick();
+    assert_eq!(sim.get("out").unwrap(), 0);
+    sim.tock();
+    assert_eq!(sim.get("out").unwrap(), 1);
+    sim.set("in", 0).unwrap();
+    sim.set("load", 0).unwrap();
+    sim.tick();
+    sim.tock();
+    assert_eq!(sim.get("out").unwrap(), 1);
+
+    let mut sim = Simulator::build(Netlist::build(&library, "PC").unwrap());
+    sim.set("inc", 1).unwrap();
+    for _ in 0..3 {
+        sim.tick();
+        sim.tock();
+    }
+    assert_eq!(sim.get("out").unwrap(), 3);
+    sim.set("reset", 1).unwrap();
+    sim.tick();
+    sim.tock();
+    assert_eq!(sim.get("out").unwrap(), 0);
+}
+
+#[test]
+fn test_builtin_ram_matches_hdl() {
+    let library_03 = library(&["01", "02", "03/a"]);
+    let mut hdl = Simulator::build(Netlist::build(&library_03, "RAM8").unwrap());
+    // Without 03/a on the path RAM8 falls back to the built-in chip.
+    let netlist = Netlist::build(&library(&["01", "02"]), "RAM8").unwrap();
+    assert_eq!(netlist.gates.len(), 1);
+    let mut builtin = Simulator::build(netlist);
+
+    for step in 0..40u64 {
+        for sim in [&mut hdl, &mut builtin] {
+            sim.set("in", step * 1237 % 65536).unwrap();
+            sim.set("load", (step % 3 == 0) as u64).unwrap();
+            sim.set("address", step * 5 % 8).unwrap();
+            sim.tick();
+            sim.tock();
+        }
+        assert_eq!(hdl.get("out").unwrap(), builtin.get("out").unwrap());
+    }
+}
+
+#[test]
+fn test_cpu_with_builtin_parts() {
+    let mut library = library(&["01", "02", "03/a", "05"]);
+    library.prefer_builtins = true;
+    let mut sim = Simulator::build(Netlist::build(&library, "CPU").unwrap());
+    // @21, then D=A
+    sim.set("instruction", 21).unwrap();
+    sim.tick();
+    sim.tock();
+    assert_eq!(sim.get("addressM").unwrap(), 21);
+    assert_eq!(sim.get("pc").unwrap(), 1);
+    sim.set("instruction", 0b1110110000010000).unwrap();
+    sim.tick();
+    sim.tock();
+    assert_eq!(sim.memory("DRegister").unwrap()[0], 21);
+}
+
+#[test]
+fn test_scripts_match_compare_files() {
+    let lib_dirs = [project_dir("01"), project_dir("02")];
+    for script in [
+        "02/ALU.tst",
+        "03/a/PC.tst",
+        "05/CPU.tst",
+        "05/ComputerMax.tst",
+    ] {
+        match script::run_script(&project_dir(script), &lib_dirs, None).unwrap() {
+            Outcome::Pass => {}
+            Outcome::Fail(message) => panic!("{}: {}", script, message),
+            Outcome::Skip(reason) => panic!("{} skipped: {}", script, reason),
+        }
+    }
+}
+
+#[test]
+fn test_script_reports_mismatch() {
+    let dir = std::env::temp_dir().join("hdlsimulator_script");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Not.tst"),
+        "load Not.hdl, compare-to Not.cmp, output-list in%B3.1.3 out%B3.1.3;\n\
+         set in 0, eval, output;\nset in 1, eval, output;",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("Not.cmp"),
+        "|  in   |  out  |\n|   0   |   1   |\n|   1   |   1   |\n",
+    )
+    .unwrap();
+    let outcome = script::run_script(&dir.join("Not.tst"), &[project_dir("01")], None).unwrap();
+    match outcome {
+        Outcome::Fail(message) => {
+            assert!(message.starts_with("line 3: comparison failure at line 3"))
+        }
+        _ => panic!("expected a comparison failure"),
+    }
+}
+
+#[test]
+fn test_set_memory_out_of_range() {
+    let dir = std::env::temp_dir().join("hdlsimulator_set_memory");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Mem.hdl"),
+        "CHIP Mem { IN in[16], load, address[3]; OUT out[16];\n\
+         PARTS: RAM8(in=in, load=load, address=address, out=out); }",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("Mem.tst"),
+        "load Mem.hdl;\nset RAM8[7] 1;\nset RAM8[9] 1;\n",
+    )
+    .unwrap();
+    match script::run_script(&dir.join("Mem.tst"), &[], None).unwrap() {
+        Outcome::Fail(message) => assert_eq!(message, "line 3: RAM8[9] is out of range"),
+        _ => panic!("expected the write past the end to fail"),
+    }
+}
+
+#[test]
+fn test_endless_repeat_fails() {
+    let dir = std::env::temp_dir().join("hdlsimulator_repeat");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Not.tst"),
+        "load Not.hdl;\nrepeat {\n    set in 1, eval;\n}\n",
+    )
+    .unwrap();
+    let outcome = script::run_script(&dir.join("Not.tst"), &[project_dir("01")], None).unwrap();
+    match outcome {
+        Outcome::Fail(message) => assert_eq!(
+            message,
+            "line 2: repeat without a count never ends, stopped after 1000 iterations"
+        ),
+        _ => panic!("expected the endless repeat to fail"),
+    }
+}
+
+#[test]
+fn test_gate_count_report() {
+    let library = library(&["01", "02", "03/a"]);
+    for name in ["Not", "Mux4Way16", "ALU"] {
+        let netlist = Netlist::build(&library, name).unwrap();
+        let report = report::build(&library, &netlist).unwrap();
+        assert_eq!(report.total.nands, netlist.gates.len(), "{}", name);
+    }
+
+    let netlist = Netlist::build(&library, "Not").unwrap();
+    let report = report::build(&library, &netlist).unwrap();
+    assert_eq!((report.depth, report.depth_end.as_str()), (1, "out"));
+
+    let netlist = Netlist::build(&library, "Register").unwrap();
+    let report = report::build(&library, &netlist).unwrap();
+    assert_eq!(report.total.dffs, 16);
+    assert_eq!(report.parts[0].name, "Bit");
+    assert_eq!(report.parts[0].instances, 16);
+    assert_eq!(report.depth_end, "a DFF input");
+}
+
+#[test]
+fn test_verilog_export() {
+    let library = library(&["01", "02", "05"]);
+    let source = verilog::export(&library, "Computer").unwrap();
+    for module in [
+        "Nand", "ALU", "CPU", "Memory", "RAM16K", "ROM32K", "PC", "Computer",
+    ] {
+        let header = format!("module {}(", module);
+        assert_eq!(source.matches(&header).count(), 1, "{}", module);
+    }
+    assert!(source.contains("module Computer(\n    input clk,\n    input reset\n);"));
+    assert!(source.contains("$readmemb(PROGRAM, memory);"));
+    assert!(source.contains("ALU p$9(.x(dRegOut), .y(aluIn), .zx(instruction[11])"));
+    // Nand comes before everything built from it.
+    assert!(source.find("module Nand(").unwrap() < source.find("module Not(").unwrap());
+
+    let dir = std::env::temp_dir().join("hdlsimulator_verilog");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Pad.hdl"),
+        "CHIP Pad { IN a[4]; OUT out[8], low; \
+         PARTS: Or8Way(in[0..1]=a[2..3], in[3]=true, out=low); \
+         Not16(in[4..7]=a, out[0..7]=out); }",
+    )
+    .unwrap();
+    let mut search_paths = vec![dir.clone()];
+    search_paths.push(project_dir("01"));
+    let source = verilog::export(&Library::build(search_paths), "Pad").unwrap();
+    assert!(source.contains("Or8Way p$0(.in({6'b000010, a[3:2]}), .out(p$0$out));"));
+    assert!(source.contains("Not16 p$1(.in({8'b00000000, a, 4'b0000}), .out(p$1$out));"));
+    assert!(source.contains("assign out = p$1$out[7:0];"));
+
+    // Internal pins named like the generated instances and wires.
+    std::fs::write(
+        dir.join("Clash.hdl"),
+        "CHIP Clash { IN a; OUT out; \
+         PARTS: Not(in=a, out=p0_out); Not(in=p0_out, out=p1); Not(in=p1, out=out); }",
+    )
+    .unwrap();
+    let search_paths = vec![dir, project_dir("01")];
+    let source = verilog::export(&Library::build(search_paths), "Clash").unwrap();
+    assert_eq!(source.matches("wire p0_out;").count(), 1);
+    assert!(source.contains("Not p$0(.in(a), .out(p$0$out));"));
+    assert!(source.contains("assign p0_out = p$0$out;"));
+    assert!(source.contains("Not p$1(.in(p0_out), .out(p$1$out));"));
+}
+
+#[test]
+fn test_lint_reports_every_problem() {
+    let source = "CHIP Bad {
+    IN a[16], b;
+    OUT out[16], flag;
+    PARTS:
+    Not16(in=a[0..7], out=x);
+    And(a=b, out=y, out=y2);
+    Or(a=y, b=z, out=y);
+    Foo(in=b, out=flag);
+    Not(in=b, o=flag, out=w);
+    Mux16(a=a, b[8..16]=a[0..7], sel=b, out=out);
+}";
+    let def = parser::parse(source, "Bad.hdl").unwrap();
+    let diagnostics: Vec<String> = lint::lint_chip(&library(&["01"]), &def)
+        .iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect();
+    assert_eq!(
+        diagnostics,
+        [
+            "Bad.hdl:3:18: warning: output pin flag is never driven",
+            "Bad.hdl:5:11: error: width mismatch, in is 16 bits but a[0..7] is 8 bits",
+            "Bad.hdl:5:27: warning: internal pin x is never read",
+            "Bad.hdl:6:5: warning: input b of And is not connected",
+            "Bad.hdl:6:25: warning: internal pin y2 is never read",
+            "Bad.hdl:7:15: error: internal pin z is never driven",
+            "Bad.hdl:7:22: error: internal pin y is driven more than once",
+            "Bad.hdl:8:5: error: chip Foo not found",
+            "Bad.hdl:9:15: error: chip Not has no pin o",
+            "Bad.hdl:9:27: warning: internal pin w is never read",
+            "Bad.hdl:10:5: warning: input b of Mux16 is not connected",
+            "Bad.hdl:10:16: error: b[8..16] is out of range for b[16]",
+        ]
+    );
+}
+
+#[test]
+fn test_vcd_dump() {
+    let path = std::env::temp_dir().join("hdlsimulator_pc.vcd");
+    let config = VcdConfig {
+        path: path.clone(),
+        internals: true,
+    };
+    let lib_dirs = [project_dir("01"), project_dir("02")];
+    let outcome = script::run_script(&project_dir("03/a/PC.tst"), &lib_dirs, Some(&config));
+    assert!(matches!(outcome.unwrap(), Outcome::Pass));
+
+    let vcd = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        vcd.starts_with("$version hdlsimulator $end\n$timescale 1ns $end\n$scope module PC $end\n")
+    );
+    assert!(vcd.contains("$var wire 16 & out [15:0] $end"));
+    assert!(vcd.contains("$scope module Register_"));
+    assert_eq!(
+        vcd.matches("$scope").count(),
+        vcd.matches("$upscope").count()
+    );
+    // PC.tst loads -32123 and the register follows on the next tock.
+    let loaded = format!("b{:b} &", -32123i16 as u16);
+    assert!(vcd.contains(&loaded));
+}
+
+#[test]
+fn test_equivalence() {
+    let dir = std::env::temp_dir().join("hdlsimulator_equiv");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("Xor.hdl"),
+        "CHIP Xor { IN a, b; OUT out; PARTS: Nand(a=a, b=b, out=n); \
+         Nand(a=a, b=n, out=x); Nand(a=n, b=a, out=y); Nand(a=x, b=y, out=out); }",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("Counter.hdl"),
+        "CHIP Counter { IN in[16], load, inc, reset; OUT out[16]; \
+         PARTS: PC(in=in, load=load, inc=inc, reset=false, out=out); }",
+    )
+    .unwrap();
+    let broken = Library::build(vec![dir.clone()]);
+
+    let xor = Netlist::build(&library(&["01"]), "Xor").unwrap();
+    let verdict = equiv::check(xor, simulator("Xor").netlist, 0).unwrap();
+    assert!(matches!(
+        verdict,
+        Verdict::Equivalent {
+            vectors: 4,
+            exhaustive: true
+        }
+    ));
+    let xor = Netlist::build(&broken, "Xor").unwrap();
+    match equiv::check(xor, simulator("Xor").netlist, 0).unwrap() {
+        Verdict::Different(example) => {
+            assert_eq!(example.steps, [[("a".into(), 0), ("b".into(), 1)]]);
+            assert_eq!(
+                (example.output.as_str(), example.left, example.right),
+                ("out", 0, 1)
+            );
+        }
+        _ => panic!("broken Xor passed"),
+    }
+
+    // The gate-level PC against the built-in one, and against a counter without reset.
+    let pc = || Netlist::build(&library(&["03/a", "01", "02"]), "PC").unwrap();
+    let builtin = Netlist::build(&Library::build(vec![]), "PC").unwrap();
+    let verdict = equiv::check(pc(), builtin, 2000).unwrap();
+    assert!(matches!(
+        verdict,
+        Verdict::Equivalent {
+            exhaustive: false,
+            ..
+        }
+    ));
+    let counter = Netlist::build(&broken, "Counter").unwrap();
+    match equiv::check(pc(), counter, 2000).unwrap() {
+        Verdict::Different(example) => assert!(example.clocked),
+        _ => panic!("counter without reset passed"),
+    }
+
+    let alu = Netlist::build(&library(&["01", "02"]), "ALU").unwrap();
+    let err = equiv::check(alu, simulator("Xor").netlist, 0)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("different inputs"));
+}
+
+#[test]
+fn test_dot_export() {
+    let library = library(&["05", "01", "02", "03/a"]);
+    let graph = dot::export(&library, "CPU", 0).unwrap();
+    assert!(graph.starts_with("digraph CPU {\n    rankdir=LR;\n"));
+    assert!(graph.contains("p9 [label=\"{{<x>x|<y>y|<zx>zx|<nx>nx|<zy>zy|<ny>ny|<f>f|<no>no}|ALU|{<out>out|<zr>zr|<ng>ng}}\", shape=record];"));
+    assert!(graph.contains("p9:out:e -> p1:a:w [label=\"aluOut[16]\"];"));
+    assert!(graph.contains("in_instruction -> p0:in:w [label=\"instruction[15]\"];"));
+    assert!(graph.contains("p24:out:e -> out_pc [label=\"pc[15]\"];"));
+    assert!(!graph.contains("subgraph"));
+
+    // One level down the ALU becomes a cluster wired through its pins.
+    let graph = dot::export(&library, "CPU", 1).unwrap();
+    assert!(graph.contains("    subgraph cluster_p9 {\n        label=\"ALU\";\n"));
+    assert!(graph.contains("p6:out:e -> p9_in_x [label=\"dRegOut[16]\"];"));
+    assert!(!graph.contains("subgraph cluster_p9_p0 {"));
+}
+
+#[test]
+fn test_computer_matches_emulator() {
+    let mut library = library(&["05", "01", "02", "03/a"]);
+    library.prefer_builtins = true;
+    let program = |name: &str| {
+        let contents = std::fs::read_to_string(project_dir("05").join(name)).unwrap();
+        cpuemulator::instruction::parse_hack(&contents).unwrap()
+    };
+    let run = |name: &str, ram: &[(u16, u16)]| {
+        let netlist = Netlist::build_with_signals(&library, "Computer").unwrap();
+        cosim::run_program(netlist, &program(name), ram, 10_000).unwrap()
+    };
+
+    // Both branches of Max, then Rect drawing four rows until it halts.
+    assert_eq!(run("Max.hack", &[(0, 3), (1, 5)]), 14);
+    assert_eq!(run("Max.hack", &[(0, 23456), (1, 12345)]), 12);
+    assert_eq!(run("Rect.hack", &[(0, 4)]), 64);
+}
+
+#[test]
+fn test_computer_memory_is_built_in() {
+    // Without --builtin only the memory is, the CPU comes from the projects down to Nand.
+    let mut library = library(&["05", "01", "02", "03/a", "03/b"]);
+    library.builtin_chips = cosim::MEMORY_CHIPS.to_vec();
+    let netlist = Netlist::build_with_signals(&library, "Computer").unwrap();
+    let contents = std::fs::read_to_string(project_dir("05").join("Max.hack")).unwrap();
+    let program = cpuemulator::instruction::parse_hack(&contents).unwrap();
+    assert_eq!(
+        cosim::run_program(netlist, &program, &[(0, 3), (1, 5)], 10_000).unwrap(),
+        14
+    );
+}
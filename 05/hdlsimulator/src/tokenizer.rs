@@ -0,0 +1,120 @@
+use std::error::Error;
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum TokenType {
+    Identifier,
+    Number,
+    Symbol,
+    Eof,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub token_type: TokenType,
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Splits HDL source into identifiers, numbers and symbols, dropping comments.
+// `..` of sub-bus ranges is returned as a single symbol.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut idx = 0;
+    let mut line = 1;
+    let mut column = 1;
+
+    let advance = |idx: &mut usize, line: &mut usize, column: &mut usize| {
+        if chars[*idx] == '\n' {
+            *line += 1;
+            *column = 1;
+        } else {
+            *column += 1;
+        }
+        *idx += 1;
+    };
+
+    while idx < chars.len() {
+        let c = chars[idx];
+        let next = chars.get(idx + 1).copied();
+
+        if c.is_whitespace() {
+            advance(&mut idx, &mut line, &mut column);
+        } else if c == '/' && next == Some('/') {
+            while idx < chars.len() && chars[idx] != '\n' {
+                advance(&mut idx, &mut line, &mut column);
+            }
+        } else if c == '/' && next == Some('*') {
+            let (start_line, start_column) = (line, column);
+            advance(&mut idx, &mut line, &mut column);
+            advance(&mut idx, &mut line, &mut column);
+            loop {
+                if idx + 1 >= chars.len() {
+                    return Err(
+                        format!("{}:{}: unterminated comment", start_line, start_column).into(),
+                    );
+                }
+                if chars[idx] == '*' && chars[idx + 1] == '/' {
+                    advance(&mut idx, &mut line, &mut column);
+                    advance(&mut idx, &mut line, &mut column);
+                    break;
+                }
+                advance(&mut idx, &mut line, &mut column);
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let (start_line, start_column) = (line, column);
+            let mut text = String::new();
+            while idx < chars.len() && (chars[idx].is_alphanumeric() || chars[idx] == '_') {
+                text.push(chars[idx]);
+                advance(&mut idx, &mut line, &mut column);
+            }
+            tokens.push(Token {
+                token_type: TokenType::Identifier,
+                text,
+                line: start_line,
+                column: start_column,
+            });
+        } else if c.is_ascii_digit() {
+            let (start_line, start_column) = (line, column);
+            let mut text = String::new();
+            while idx < chars.len() && chars[idx].is_ascii_digit() {
+                text.push(chars[idx]);
+                advance(&mut idx, &mut line, &mut column);
+            }
+            tokens.push(Token {
+                token_type: TokenType::Number,
+                text,
+                line: start_line,
+                column: start_column,
+            });
+        } else if c == '.' && next == Some('.') {
+            tokens.push(Token {
+                token_type: TokenType::Symbol,
+                text: String::from(".."),
+                line,
+                column,
+            });
+            advance(&mut idx, &mut line, &mut column);
+            advance(&mut idx, &mut line, &mut column);
+        } else if "{}()[];,=:".contains(c) {
+            tokens.push(Token {
+                token_type: TokenType::Symbol,
+                text: c.to_string(),
+                line,
+                column,
+            });
+            advance(&mut idx, &mut line, &mut column);
+        } else {
+            return Err(format!("{}:{}: unexpected character '{}'", line, column, c).into());
+        }
+    }
+
+    tokens.push(Token {
+        token_type: TokenType::Eof,
+        text: String::new(),
+        line,
+        column,
+    });
+    Ok(tokens)
+}
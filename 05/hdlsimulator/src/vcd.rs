@@ -0,0 +1,144 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::netlist::{Net, Signal};
+use crate::simulator::Simulator;
+
+pub struct VcdConfig {
+    pub path: PathBuf,
+    // Also dump the internal pins of every part, not only the chip's own pins.
+    pub internals: bool,
+}
+
+struct Variable {
+    id: String,
+    nets: Vec<Net>,
+    last: Option<u64>,
+}
+
+// Writes a Value Change Dump of a simulation. Every eval, tick and tock is one time step,
+// and a `clk` variable is high between tick and tock.
+pub struct VcdWriter {
+    out: BufWriter<File>,
+    variables: Vec<Variable>,
+    clock: Option<bool>,
+    time: u64,
+}
+
+// Identifier codes use the printable characters from '!' to '~'.
+fn identifier(mut idx: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (idx % 94) as u8) as char);
+        idx /= 94;
+        if idx == 0 {
+            return id;
+        }
+        idx -= 1;
+    }
+}
+
+impl VcdWriter {
+    pub fn create(config: &VcdConfig, simulator: &Simulator) -> Result<VcdWriter, Box<dyn Error>> {
+        let file = File::create(&config.path)
+            .map_err(|err| format!("{}: {}", config.path.display(), err))?;
+        let netlist = &simulator.netlist;
+
+        // Without internals, only the chip's own pins in the top scope.
+        let signals: Vec<Signal> = match config.internals && !netlist.signals.is_empty() {
+            true => netlist.signals.clone(),
+            false => netlist
+                .inputs
+                .iter()
+                .chain(netlist.outputs.iter())
+                .map(|port| Signal {
+                    scope: vec![netlist.name.clone()],
+                    name: port.name.clone(),
+                    nets: port.nets.clone(),
+                })
+                .collect(),
+        };
+
+        let mut out = BufWriter::new(file);
+        writeln!(out, "$version hdlsimulator $end")?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module {} $end", netlist.name)?;
+        writeln!(out, "$var wire 1 ! clk $end")?;
+        let mut scope = vec![netlist.name.clone()];
+        let mut variables = Vec::new();
+        for signal in signals {
+            // Close the scopes that end here and open the new ones.
+            let common = scope
+                .iter()
+                .zip(signal.scope.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            for _ in common..scope.len() {
+                writeln!(out, "$upscope $end")?;
+            }
+            for name in &signal.scope[common..] {
+                writeln!(out, "$scope module {} $end", name)?;
+            }
+            scope = signal.scope.clone();
+
+            let id = identifier(variables.len() + 1);
+            match signal.nets.len() {
+                1 => writeln!(out, "$var wire 1 {} {} $end", id, signal.name)?,
+                width => writeln!(
+                    out,
+                    "$var wire {} {} {} [{}:0] $end",
+                    width,
+                    id,
+                    signal.name,
+                    width - 1
+                )?,
+            }
+            variables.push(Variable {
+                id,
+                nets: signal.nets,
+                last: None,
+            });
+        }
+        for _ in 0..scope.len() {
+            writeln!(out, "$upscope $end")?;
+        }
+        writeln!(out, "$enddefinitions $end")?;
+
+        Ok(VcdWriter {
+            out,
+            variables,
+            clock: None,
+            time: 0,
+        })
+    }
+
+    // Records the values that changed since the last sample.
+    pub fn sample(&mut self, simulator: &Simulator, clock: bool) -> Result<(), Box<dyn Error>> {
+        writeln!(self.out, "#{}", self.time)?;
+        self.time += 1;
+        if self.clock != Some(clock) {
+            writeln!(self.out, "{}!", clock as u8)?;
+            self.clock = Some(clock);
+        }
+        for variable in self.variables.iter_mut() {
+            let value = simulator.read_nets(&variable.nets);
+            if variable.last == Some(value) {
+                continue;
+            }
+            variable.last = Some(value);
+            match variable.nets.len() {
+                1 => writeln!(self.out, "{}{}", value, variable.id)?,
+                _ => writeln!(self.out, "b{:b} {}", value, variable.id)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        writeln!(self.out, "#{}", self.time)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
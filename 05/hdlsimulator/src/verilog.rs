@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::builtin::Builtin;
+use crate::chip::{ChipDef, Library, PinRef};
+
+const KEYWORDS: [&str; 24] = [
+    "and",
+    "assign",
+    "begin",
+    "buf",
+    "case",
+    "default",
+    "else",
+    "end",
+    "endmodule",
+    "for",
+    "if",
+    "initial",
+    "inout",
+    "input",
+    "module",
+    "nand",
+    "nor",
+    "not",
+    "or",
+    "output",
+    "reg",
+    "wire",
+    "xnor",
+    "xor",
+];
+
+const NAND: &str = "module Nand(input a, input b, output out);
+    assign out = ~(a & b);
+endmodule
+";
+
+const DFF: &str = "module DFF(input clk, input in, output reg out);
+    initial out = 1'b0;
+    always @(posedge clk) out <= in;
+endmodule
+";
+
+// One bit feeding a part input: a constant or a bit of a signal (None for 1-bit signals).
+#[derive(PartialEq, Clone, Debug)]
+enum Source {
+    Constant(bool),
+    Signal(String, usize, Option<usize>),
+}
+
+fn identifier(name: &str) -> String {
+    if KEYWORDS.contains(&name) {
+        format!("\\{} ", name)
+    } else {
+        String::from(name)
+    }
+}
+
+// `name`, `name[i]` or `name[to:from]` for bits from..=to of a signal of the given width.
+fn slice(name: &str, width: usize, from: usize, to: usize) -> String {
+    let name = identifier(name);
+    if width == 1 || (from == 0 && to + 1 == width) {
+        name
+    } else if from == to {
+        format!("{}[{}]", name, from)
+    } else {
+        format!("{}[{}:{}]", name, to, from)
+    }
+}
+
+fn declaration(kind: &str, name: &str, width: usize) -> String {
+    match width {
+        1 => format!("{} {}", kind, identifier(name)),
+        _ => format!("{} [{}:0] {}", kind, width - 1, identifier(name)),
+    }
+}
+
+// Verilog concatenation of the bits, most significant first.
+fn concatenation(bits: &[Source]) -> String {
+    let mut groups: Vec<String> = Vec::new();
+    let mut idx = bits.len();
+    while idx > 0 {
+        let top = idx - 1;
+        let mut bottom = top;
+        match &bits[top] {
+            Source::Constant(_) => {
+                while bottom > 0 && matches!(bits[bottom - 1], Source::Constant(_)) {
+                    bottom -= 1;
+                }
+                let digits: String = bits[bottom..=top]
+                    .iter()
+                    .rev()
+                    .map(|bit| {
+                        if bit == &Source::Constant(true) {
+                            '1'
+                        } else {
+                            '0'
+                        }
+                    })
+                    .collect();
+                groups.push(format!("{}'b{}", top - bottom + 1, digits));
+            }
+            Source::Signal(name, width, Some(high)) => {
+                let mut low = *high;
+                while bottom > 0
+                    && low > 0
+                    && bits[bottom - 1] == Source::Signal(name.clone(), *width, Some(low - 1))
+                {
+                    bottom -= 1;
+                    low -= 1;
+                }
+                groups.push(slice(name, *width, low, *high));
+            }
+            Source::Signal(name, _, None) => groups.push(identifier(name)),
+        }
+        idx = bottom;
+    }
+    match groups.len() {
+        1 => groups.remove(0),
+        _ => format!("{{{}}}", groups.join(", ")),
+    }
+}
+
+struct Exporter<'a> {
+    library: &'a Library,
+    // Module source per chip, in the order they are completed so parts come first.
+    modules: Vec<String>,
+    clocked: HashMap<String, bool>,
+}
+
+impl Exporter<'_> {
+    // Emits the chip and its parts, returning whether it takes the clock.
+    fn export(&mut self, def: &ChipDef) -> Result<bool, Box<dyn Error>> {
+        if let Some(clocked) = self.clocked.get(&def.name) {
+            return Ok(*clocked);
+        }
+
+        let (source, clocked) = if def.is_primitive() {
+            (String::from(NAND), false)
+        } else if let Some(builtin) = &def.builtin {
+            let chip = Builtin::from_name(builtin)
+                .ok_or_else(|| format!("{}: unknown built-in chip {}", def.location, builtin))?;
+            (builtin_module(&def.name, chip), chip.is_clocked())
+        } else {
+            let mut children = Vec::new();
+            let mut clocked = false;
+            for part in &def.parts {
+                let child = self
+                    .library
+                    .load(&part.name)
+                    .map_err(|err| format!("{}: {}", part.location, err))?;
+                let child_clocked = self.export(&child)?;
+                clocked |= child_clocked;
+                children.push((child, child_clocked));
+            }
+            (self.chip_module(def, &children, clocked)?, clocked)
+        };
+
+        self.clocked.insert(def.name.clone(), clocked);
+        self.modules.push(source);
+        Ok(clocked)
+    }
+
+    fn chip_module(
+        &self,
+        def: &ChipDef,
+        children: &[(Rc<ChipDef>, bool)],
+        clocked: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut ports = Vec::new();
+        if clocked {
+            ports.push(String::from("input clk"));
+        }
+        ports.extend(
+            def.inputs
+                .iter()
+                .map(|pin| declaration("input", &pin.name, pin.width)),
+        );
+        ports.extend(
+            def.outputs
+                .iter()
+                .map(|pin| declaration("output", &pin.name, pin.width)),
+        );
+
+        // Internal pins take the width of the part outputs driving them.
+        let mut widths: HashMap<&str, usize> = def
+            .inputs
+            .iter()
+            .chain(def.outputs.iter())
+            .map(|pin| (pin.name.as_str(), pin.width))
+            .collect();
+        let mut wires = Vec::new();
+        for (part, (child, _)) in def.parts.iter().zip(children.iter()) {
+            for conn in &part.connections {
+                let pin = child.pin(&conn.pin.name).ok_or_else(|| {
+                    format!(
+                        "{}: chip {} has no pin {}",
+                        conn.pin.location, child.name, conn.pin.name
+                    )
+                })?;
+                let name = conn.signal.name.as_str();
+                if child.output(&pin.name).is_some()
+                    && !conn.signal.is_constant()
+                    && !widths.contains_key(name)
+                {
+                    let width = conn.pin.bits(pin.width).len();
+                    widths.insert(name, width);
+                    wires.push(format!("    {};", declaration("wire", name, width)));
+                }
+            }
+        }
+
+        let mut body = Vec::new();
+        for (idx, (part, (child, child_clocked))) in
+            def.parts.iter().zip(children.iter()).enumerate()
+        {
+            // HDL names can't contain '$', so generated names never clash with the chip's pins.
+            let instance = format!("p${}", idx);
+            let mut connections = Vec::new();
+            let mut assigns = Vec::new();
+            if *child_clocked {
+                connections.push(String::from(".clk(clk)"));
+            }
+            for pin in &child.inputs {
+                let mut bits = vec![Source::Constant(false); pin.width];
+                for conn in part.connections.iter().filter(|c| c.pin.name == pin.name) {
+                    let range = conn.pin.bits(pin.width);
+                    for (offset, bit) in range.enumerate() {
+                        bits[bit] = self.source(&conn.signal, offset, &widths)?;
+                    }
+                }
+                connections.push(format!(".{}({})", pin.name, concatenation(&bits)));
+            }
+            // Outputs go through a wire per pin, so each sub-bus becomes one assign.
+            for pin in &child.outputs {
+                let wire = format!("{}${}", instance, pin.name);
+                wires.push(format!("    {};", declaration("wire", &wire, pin.width)));
+                connections.push(format!(".{}({})", pin.name, wire));
+                for conn in part.connections.iter().filter(|c| c.pin.name == pin.name) {
+                    if conn.signal.is_constant() {
+                        continue;
+                    }
+                    let range = conn.pin.bits(pin.width);
+                    let width = widths[conn.signal.name.as_str()];
+                    let target = conn.signal.bits(width);
+                    assigns.push(format!(
+                        "    assign {} = {};",
+                        slice(&conn.signal.name, width, target.start, target.end - 1),
+                        slice(&wire, pin.width, range.start, range.end - 1)
+                    ));
+                }
+            }
+            body.push(format!(
+                "    {} {}({});",
+                identifier(&child.name),
+                instance,
+                connections.join(", ")
+            ));
+            body.extend(assigns);
+        }
+
+        Ok(format!(
+            "module {}(\n    {}\n);\n{}\n{}\nendmodule\n",
+            identifier(&def.name),
+            ports.join(",\n    "),
+            wires.join("\n"),
+            body.join("\n")
+        ))
+    }
+
+    fn source(
+        &self,
+        signal: &PinRef,
+        offset: usize,
+        widths: &HashMap<&str, usize>,
+    ) -> Result<Source, Box<dyn Error>> {
+        if signal.is_constant() {
+            return Ok(Source::Constant(signal.name == "true"));
+        }
+        let width = *widths.get(signal.name.as_str()).ok_or_else(|| {
+            format!(
+                "{}: internal pin {} is never driven",
+                signal.location, signal.name
+            )
+        })?;
+        let bit = signal.bits(width).start + offset;
+        let index = if width == 1 { None } else { Some(bit) };
+        Ok(Source::Signal(signal.name.clone(), width, index))
+    }
+}
+
+// Behavioural modules for the built-in chips. Memories are inferred from the reg arrays,
+// ROM32K loads its program with $readmemb, which reads .hack files as they are.
+fn builtin_module(name: &str, chip: Builtin) -> String {
+    match chip {
+        Builtin::Dff => String::from(DFF),
+        Builtin::Bit => format!(
+            "module {}(input clk, input in, input load, output reg out);
+    initial out = 1'b0;
+    always @(posedge clk) if (load) out <= in;
+endmodule
+",
+            name
+        ),
+        Builtin::Register => format!(
+            "module {}(input clk, input [15:0] in, input load, output reg [15:0] out);
+    initial out = 16'b0;
+    always @(posedge clk) if (load) out <= in;
+endmodule
+",
+            name
+        ),
+        Builtin::Ram(address_bits) => memory_module(name, address_bits),
+        Builtin::Screen => memory_module(name, 13),
+        Builtin::Rom32K => format!(
+            "module {}(input [14:0] address, output [15:0] out);
+    parameter PROGRAM = \"program.hack\";
+    reg [15:0] memory [0:32767];
+    initial $readmemb(PROGRAM, memory);
+    assign out = memory[address];
+endmodule
+",
+            name
+        ),
+        // Replace with the board's keyboard controller.
+        Builtin::Keyboard => format!(
+            "module {}(output [15:0] out);
+    assign out = 16'b0;
+endmodule
+",
+            name
+        ),
+        Builtin::PC => format!(
+            "module {}(input clk, input [15:0] in, input load, input inc, input reset, output reg [15:0] out);
+    initial out = 16'b0;
+    always @(posedge clk)
+        if (reset) out <= 16'b0;
+        else if (load) out <= in;
+        else if (inc) out <= out + 16'b1;
+endmodule
+",
+            name
+        ),
+    }
+}
+
+fn memory_module(name: &str, address_bits: usize) -> String {
+    format!(
+        "module {}(input clk, input [15:0] in, input load, input [{}:0] address, output [15:0] out);
+    reg [15:0] memory [0:{}];
+    assign out = memory[address];
+    always @(posedge clk) if (load) memory[address] <= in;
+endmodule
+",
+        name,
+        address_bits - 1,
+        (1 << address_bits) - 1
+    )
+}
+
+// Translates the chip and every chip it uses into Verilog modules, parts first.
+// Clocked chips get a `clk` input, and tick/tock become its rising edge.
+pub fn export(library: &Library, name: &str) -> Result<String, Box<dyn Error>> {
+    let def = library.load_top(name)?;
+    let mut exporter = Exporter {
+        library,
+        modules: Vec::new(),
+        clocked: HashMap::new(),
+    };
+    exporter.export(&def)?;
+    Ok(exporter.modules.join("\n"))
+}
@@ -1,4 +1,10 @@
-use std::{collections::HashMap, error::Error, fs, ops::Index, process};
+use std::{collections::HashMap, error::Error, fmt, fs, ops::Index};
+
+#[cfg(test)]
+mod tests;
+
+// Instructions the ROM32K holds.
+pub const ROM_SIZE: usize = 32768;
 
 pub struct Config {
     pub in_file: String,
@@ -39,21 +45,21 @@ impl SymbolTable {
         // dbg!(symbols);
 
         SymbolTable {
-            symbols: symbols,
+            symbols,
             next_free_variable: 16,
         }
     }
 
-    pub fn add_entry(&mut self, symbol: &String, address: u16) {
-        self.symbols.insert(symbol.clone(), address);
+    pub fn add_entry(&mut self, symbol: &str, address: u16) {
+        self.symbols.insert(symbol.to_string(), address);
     }
 
-    pub fn contains(&self, symbol: &String) -> bool {
-        return self.symbols.contains_key(symbol);
+    pub fn contains(&self, symbol: &str) -> bool {
+        self.symbols.contains_key(symbol)
     }
 
-    pub fn get_address(&self, symbol: &String) -> Option<&u16> {
-        return self.symbols.get(symbol.as_str());
+    pub fn get_address(&self, symbol: &str) -> Option<&u16> {
+        self.symbols.get(symbol)
     }
 }
 
@@ -71,19 +77,17 @@ struct Parser {
     instructions: HashMap<i32, String>,
 }
 impl Parser {
-    pub fn build(in_file: &str) -> Result<Parser, Box<dyn Error>> {
-        let contents = fs::read_to_string(in_file)?;
-        let lines = contents.split("\n");
-        return Ok(Parser {
-            lines: lines
-                .into_iter()
+    pub fn new(source: &str) -> Parser {
+        Parser {
+            lines: source
+                .lines()
                 .map(|line| String::from(line.trim()))
                 .filter(|line| !line.starts_with("//") && !line.is_empty())
                 .collect(),
             current_line: -1,
             instr_line: -1,
             instructions: HashMap::new(),
-        });
+        }
     }
 
     pub fn reset_to_start(&mut self) {
@@ -91,40 +95,40 @@ impl Parser {
         self.instr_line = -1;
     }
 
-    pub fn command_type(&self) -> CommandType {
+    // The ROM address of the current instruction, or of the next one for a label.
+    pub fn address(&self) -> usize {
+        self.instr_line.max(0) as usize
+    }
+
+    pub fn command_type(&self) -> Result<CommandType, String> {
         let instr = self.instructions.get(&self.instr_line).unwrap();
 
-        if instr.starts_with("@") {
-            return CommandType::ACommand;
-        } else if instr.contains(";") || instr.contains("=") {
-            return CommandType::CComand;
-        } else if instr.contains("(") {
-            return CommandType::LCommand;
+        if instr.starts_with('@') {
+            Ok(CommandType::ACommand)
+        } else if instr.contains(';') || instr.contains('=') {
+            Ok(CommandType::CComand)
+        } else if instr.starts_with('(') && instr.ends_with(')') {
+            Ok(CommandType::LCommand)
+        } else {
+            Err(format!("invalid instruction {instr}"))
         }
-
-        panic!("invalid instruction type {instr}");
     }
 
     pub fn has_more_commands(&self, first_pass: bool) -> bool {
-        if (self.current_line + 1) as usize == self.lines.len() && first_pass {
-            return false;
-        } else if (self.instr_line + 1) as usize == self.instructions.len() && !first_pass {
-            return false;
-        }
-        return true;
+        !(first_pass && (self.current_line + 1) as usize == self.lines.len()
+            || !first_pass && (self.instr_line + 1) as usize == self.instructions.len())
     }
 
-    pub fn advance(&mut self, st: &mut SymbolTable, first_pass: bool) {
+    pub fn advance(&mut self, st: &mut SymbolTable, first_pass: bool) -> Result<(), String> {
         if !self.has_more_commands(first_pass) {
-            return;
+            return Ok(());
         }
 
         self.current_line += 1;
         let mut line = self.lines.index(self.current_line as usize).trim();
 
         if line.starts_with("//") || line.is_empty() {
-            self.advance(st, first_pass);
-            return;
+            return self.advance(st, first_pass);
         }
 
         let binding = self.clean_line(line);
@@ -141,37 +145,41 @@ impl Parser {
                 .insert(self.instr_line, String::from(line));
         }
 
-        let command_type = self.command_type();
+        let command_type = self.command_type()?;
         if first_pass && command_type == CommandType::LCommand {
             let symbol = self.symbol();
-            if !st.contains(&symbol) {
-                st.add_entry(&symbol, self.instr_line as u16);
+            if !is_symbol(&symbol) {
+                return Err(format!("invalid label ({symbol})"));
+            }
+            if st.contains(&symbol) {
+                return Err(format!("duplicate label ({symbol})"));
             }
+            st.add_entry(&symbol, self.instr_line as u16);
             self.instr_line -= 1;
         } else if !first_pass && command_type == CommandType::ACommand {
             let symbol = self.symbol();
-            let mut is_num = true;
-            let num = symbol.trim().parse::<u16>().unwrap_or_else(|_| {
-                is_num = false;
-                0
-            });
-            if is_num {
+            if let Ok(num) = symbol.parse::<u16>() {
+                if num > 32767 {
+                    return Err(format!("constant {num} does not fit in 15 bits"));
+                }
                 st.add_entry(&symbol, num);
+            } else if !is_symbol(&symbol) {
+                return Err(format!("invalid symbol @{symbol}"));
             } else if !st.contains(&symbol) {
                 st.add_entry(&symbol, st.next_free_variable);
                 st.next_free_variable += 1;
             }
         }
-        // println!("{line}")
+        Ok(())
     }
 
     pub fn symbol(&self) -> String {
         let instr = self.instructions.get(&self.instr_line).unwrap();
         let size = instr.len();
-        if instr.contains("@") {
+        if instr.contains('@') {
             let value = &instr[1..size];
             return String::from(value.trim());
-        } else if instr.contains("(") {
+        } else if instr.contains('(') {
             let value = &instr[1..size - 1];
             return String::from(value.trim());
         }
@@ -181,61 +189,57 @@ impl Parser {
 
     pub fn dest(&self) -> String {
         let instr = self.instructions.get(&self.instr_line).unwrap();
-        let tokens: Vec<&str> = instr.split("=").map(|val| val.trim()).collect();
+        let tokens: Vec<&str> = instr.split('=').map(|val| val.trim()).collect();
         if tokens.len() != 1 {
             return String::from(tokens[0]);
         }
-        return String::from("null");
+        String::from("null")
     }
 
     pub fn comp(&self) -> String {
         let instr = self.instructions.get(&self.instr_line).unwrap();
 
-        let tokens: Vec<&str>;
-        if instr.contains(";") {
-            // jump operation
-            tokens = instr.split(";").map(|val| val.trim()).collect();
-            return String::from(tokens[0]);
-        } else {
-            // assignment
-            tokens = instr.split("=").map(|val| val.trim()).collect();
-            return String::from(tokens[1]);
-        }
+        // jump operation, possibly with an assignment
+        let comp = instr.split(';').next().unwrap_or_default();
+        String::from(comp.rsplit('=').next().unwrap_or_default().trim())
     }
 
     pub fn jump(&self) -> String {
         let instr = self.instructions.get(&self.instr_line).unwrap();
         // jump operation
-        let tokens: Vec<&str> = instr.split(";").map(|val| val.trim()).collect();
+        let tokens: Vec<&str> = instr.split(';').map(|val| val.trim()).collect();
 
         if tokens.len() == 1 {
             // semi-colon not found
             return String::from("null");
         }
 
-        return String::from(tokens[1]);
+        String::from(tokens[1])
     }
 
     pub fn clean_line(&self, line: &str) -> String {
-        let mut clean = line.trim();
-
-        let idx = match clean.find("//") {
-            Some(x) => x,
-            None => 123456,
+        let clean = match line.find("//") {
+            Some(idx) => &line[0..idx],
+            None => line,
         };
-
-        if idx != 123456 {
-            clean = &clean[0..idx];
-        }
-
-        return String::from(clean);
+        String::from(clean.trim())
     }
 }
 
+// Symbols are letters, digits and `_.$:` not starting with a digit, plus `-` which the
+// labels of our Jack compiler use after the first character.
+fn is_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && !symbol.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:-".contains(c))
+}
+
 struct Code {}
 impl Code {
     fn new() -> Code {
-        return Code {};
+        Code {}
     }
     pub fn comp(&self, mnemonic: &str) -> Result<String, Box<dyn Error>> {
         let comp = match mnemonic {
@@ -257,30 +261,30 @@ impl Code {
             "D-1" => "001110",
             "A-1" => "110010",
             "M-1" => "110010",
-            "D+A" => "000010",
-            "D+M" => "000010",
+            "D+A" | "A+D" => "000010",
+            "D+M" | "M+D" => "000010",
             "D-A" => "010011",
             "D-M" => "010011",
             "A-D" => "000111",
             "M-D" => "000111",
-            "D&A" => "000000",
-            "D&M" => "000000",
-            "D|A" => "010101",
-            "D|M" => "010101",
+            "D&A" | "A&D" => "000000",
+            "D&M" | "M&D" => "000000",
+            "D|A" | "A|D" => "010101",
+            "D|M" | "M|D" => "010101",
             _ => "invalid",
         };
 
         if comp == "invalid" {
-            return Err("invalid computation {mnemonic}".into());
+            return Err(format!("invalid computation: {mnemonic}").into());
         }
 
         let mut result = String::from(comp);
-        if mnemonic.contains("M") {
+        if mnemonic.contains('M') {
             result.insert(0, '1');
         } else {
             result.insert(0, '0');
         }
-        return Ok(result);
+        Ok(result)
     }
 
     pub fn dest(&self, mnemonic: &str) -> Result<String, Box<dyn Error>> {
@@ -300,7 +304,7 @@ impl Code {
             return Err(format!("invalid destination: {mnemonic}").into());
         }
 
-        return Ok(String::from(dest));
+        Ok(String::from(dest))
     }
 
     fn jump(&self, mnemonic: &str) -> Result<String, Box<dyn Error>> {
@@ -318,20 +322,52 @@ impl Code {
         if jump == "invalid" {
             return Err(format!("invalid jump: {mnemonic}").into());
         }
-        return Ok(String::from(jump));
+        Ok(String::from(jump))
     }
 }
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut parser = Parser::build(config.in_file.as_str()).unwrap();
+// An error in the instruction at ROM address `address`, or in the label before it. Errors
+// about the whole program have no address.
+#[derive(Debug)]
+pub struct AssemblyError {
+    pub address: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address {
+            Some(address) => write!(f, "instruction {}: {}", address, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for AssemblyError {}
+
+// Translates Hack assembly to the machine words of the ROM.
+pub fn assemble(source: &str) -> Result<Vec<u16>, AssemblyError> {
+    let mut parser = Parser::new(source);
     let mut st = SymbolTable::new();
+    let error = |parser: &Parser, message: String| AssemblyError {
+        address: Some(parser.address()),
+        message,
+    };
 
     // Build symbol table
-    loop {
-        if !parser.has_more_commands(true) {
-            break;
-        }
-        parser.advance(&mut st, true);
+    while parser.has_more_commands(true) {
+        parser
+            .advance(&mut st, true)
+            .map_err(|err| error(&parser, err))?;
+    }
+    if parser.instructions.len() > ROM_SIZE {
+        return Err(AssemblyError {
+            address: None,
+            message: format!(
+                "{} instructions do not fit in the 32K ROM",
+                parser.instructions.len()
+            ),
+        });
     }
 
     // Reset parser
@@ -339,36 +375,47 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
 
     // Compile program
     let code = Code::new();
-    let mut out_lines: Vec<String> = Vec::new();
-    loop {
-        if !parser.has_more_commands(false) {
-            break;
-        }
-        parser.advance(&mut st, false);
-
-        if parser.command_type() == CommandType::CComand {
-            let comp = code.comp(&parser.comp()).unwrap();
-            let dest = code.dest(&parser.dest()).unwrap();
-            let jump = code.jump(&parser.jump()).unwrap();
-            out_lines.push(format!("111{:#7}{:#3}{:#3}", comp, dest, jump));
-        } else if parser.command_type() == CommandType::ACommand {
-            let symbol = parser.symbol();
-            let addr = st.get_address(&symbol).unwrap_or_else(|| {
-                println!("Error: undefined variable {symbol}");
-                process::exit(1);
-            });
-            out_lines.push(format!("{addr:016b}"));
-        } else if parser.command_type() == CommandType::LCommand {
-            continue;
+    let mut words: Vec<u16> = Vec::new();
+    while parser.has_more_commands(false) {
+        parser
+            .advance(&mut st, false)
+            .map_err(|err| error(&parser, err))?;
+
+        match parser.command_type().map_err(|err| error(&parser, err))? {
+            CommandType::CComand => {
+                let fields = code.comp(&parser.comp()).and_then(|comp| {
+                    Ok(format!(
+                        "111{}{}{}",
+                        comp,
+                        code.dest(&parser.dest())?,
+                        code.jump(&parser.jump())?
+                    ))
+                });
+                let fields = fields.map_err(|err| error(&parser, err.to_string()))?;
+                words.push(u16::from_str_radix(&fields, 2).unwrap());
+            }
+            CommandType::ACommand => {
+                let symbol = parser.symbol();
+                words.push(*st.get_address(&symbol).unwrap());
+            }
+            CommandType::LCommand => continue,
         }
     }
 
+    Ok(words)
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let source = fs::read_to_string(&config.in_file)
+        .map_err(|err| format!("{}: {}", config.in_file, err))?;
+    let words = assemble(&source).map_err(|err| format!("{}: {}", config.in_file, err))?;
+
     // Write .hack file
     fs::write(
         config.out_file,
-        out_lines
+        words
             .iter()
-            .map(|line| format!("{}\n", line))
+            .map(|word| format!("{:016b}\n", word))
             .collect::<Vec<String>>()
             .concat(),
     )?;
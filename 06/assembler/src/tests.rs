@@ -0,0 +1,34 @@
+use crate::{assemble, ROM_SIZE};
+
+#[test]
+fn test_assemble() {
+    let words = assemble("// add\n@2\nD=A\n(LOOP)\n@x\nM=D+M\n@LOOP\n0;JMP\n").unwrap();
+    assert_eq!(words, [2, 0xEC10, 16, 0xF088, 2, 0xEA87]);
+}
+
+#[test]
+fn test_assembly_errors() {
+    let message = |source: &str| assemble(source).unwrap_err().to_string();
+    assert_eq!(
+        message("(LOOP)\n@LOOP\n(LOOP)\n0;JMP\n"),
+        "instruction 1: duplicate label (LOOP)"
+    );
+    assert_eq!(
+        message("@1\n@32768\n"),
+        "instruction 1: constant 32768 does not fit in 15 bits"
+    );
+    assert_eq!(message("@a+b\n"), "instruction 0: invalid symbol @a+b");
+    assert_eq!(message("@-1\n"), "instruction 0: invalid symbol @-1");
+    assert_eq!(
+        message("@1\n(1ABEL)\n"),
+        "instruction 1: invalid label (1ABEL)"
+    );
+
+    // Too long programs fail as a whole, not at an instruction.
+    let err = assemble(&"@0\n".repeat(ROM_SIZE + 1)).unwrap_err();
+    assert_eq!(err.address, None);
+    assert_eq!(
+        err.to_string(),
+        "32769 instructions do not fit in the 32K ROM"
+    );
+}
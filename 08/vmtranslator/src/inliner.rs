@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use crate::{Parser, Segment, VmCommand};
+
+// Functions with at most this many commands are inlined.
+const MAX_COMMANDS: usize = 8;
+
+// A function that can be inlined, with the stack depth before each command above its
+// arguments, locals and saved pointers.
+struct Inlinable {
+    file_name: String,
+    locals: u16,
+    body: Vec<(VmCommand, Option<u16>)>,
+    // The pointers the function pops to, which the call would restore.
+    pointers: Vec<u16>,
+    labels: Vec<String>,
+    // Statics belong to the file, so the body only fits into its own file.
+    uses_statics: bool,
+}
+
+// The stack depth before each command, None for unreachable ones, following every path
+// from the start. Fails if the depths at a label disagree, the body pops more than it pushed,
+// jumps to a missing label or runs off its end.
+fn stack_depths(body: &[VmCommand]) -> Option<Vec<Option<u16>>> {
+    let labels: HashMap<&str, usize> = body
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, command)| match command {
+            VmCommand::Label(label) => Some((label.as_str(), idx)),
+            _ => None,
+        })
+        .collect();
+    let mut depths = vec![None; body.len()];
+    let mut pending = vec![(0, 0)];
+    while let Some((idx, depth)) = pending.pop() {
+        let command = body.get(idx)?;
+        match depths[idx] {
+            Some(other) if other == depth => continue,
+            Some(_) => return None,
+            None => depths[idx] = Some(depth),
+        }
+        let after = u16::try_from(depth as i32 + command.stack_effect()).ok()?;
+        match command {
+            VmCommand::Goto(label) => pending.push((*labels.get(label.as_str())?, after)),
+            VmCommand::IfGoto(label) => {
+                pending.push((*labels.get(label.as_str())?, after));
+                pending.push((idx + 1, after));
+            }
+            VmCommand::Return => {}
+            _ => pending.push((idx + 1, after)),
+        }
+    }
+    Some(depths)
+}
+
+fn inlinable(name: &str, file_name: &str, locals: u16, body: &[VmCommand]) -> Option<Inlinable> {
+    // Functions that never return, like loops waiting for a reset, gain nothing.
+    if body.len() > MAX_COMMANDS || !body.contains(&VmCommand::Return) {
+        return None;
+    }
+    let mut pointers = Vec::new();
+    let mut labels = Vec::new();
+    let mut uses_statics = false;
+    for command in body {
+        match command {
+            VmCommand::Call(callee, _) if callee == name => return None,
+            VmCommand::Push(Segment::Local, index) | VmCommand::Pop(Segment::Local, index)
+                if *index >= locals =>
+            {
+                return None
+            }
+            VmCommand::Pop(Segment::Pointer, index) if !pointers.contains(index) => {
+                pointers.push(*index)
+            }
+            VmCommand::Label(label) => labels.push(label.clone()),
+            VmCommand::Push(Segment::Static, _) | VmCommand::Pop(Segment::Static, _) => {
+                uses_statics = true
+            }
+            _ => {}
+        }
+    }
+    let depths = stack_depths(body)?;
+    Some(Inlinable {
+        file_name: file_name.to_string(),
+        locals,
+        body: body.iter().cloned().zip(depths).collect(),
+        pointers,
+        labels,
+        uses_statics,
+    })
+}
+
+impl Inlinable {
+    // The body for a call with `args` arguments, its labels renamed with `suffix`. The
+    // arguments, locals and saved pointers are stack slots below what the body pushes.
+    fn expand(&self, args: u16, suffix: &str) -> Option<Vec<VmCommand>> {
+        let rename = |label: &str| format!("{}{}", label, suffix);
+        let end = rename("END");
+        let base = args + self.locals + self.pointers.len() as u16;
+        let mut commands = vec![VmCommand::Push(Segment::Constant, 0); self.locals as usize];
+        for pointer in &self.pointers {
+            commands.push(VmCommand::Push(Segment::Pointer, *pointer));
+        }
+
+        let mut jumps_to_end = false;
+        for (idx, (command, depth)) in self.body.iter().enumerate() {
+            // Unreachable commands are left out.
+            let Some(depth) = depth else {
+                continue;
+            };
+            let depth = base + depth;
+            let slot = |segment: Segment, index: u16| match segment {
+                Segment::Argument if index < args => Some(depth - index),
+                Segment::Local => Some(depth - args - index),
+                _ => None,
+            };
+            let command = match command {
+                VmCommand::Push(segment @ (Segment::Argument | Segment::Local), index) => {
+                    VmCommand::PushStack(slot(*segment, *index)?)
+                }
+                VmCommand::Pop(segment @ (Segment::Argument | Segment::Local), index) => {
+                    VmCommand::PopStack(slot(*segment, *index)?)
+                }
+                VmCommand::Label(label) => VmCommand::Label(rename(label)),
+                VmCommand::Goto(label) => VmCommand::Goto(rename(label)),
+                VmCommand::IfGoto(label) => VmCommand::IfGoto(rename(label)),
+                VmCommand::Return => {
+                    // Restore the pointers, then replace the arguments by the result.
+                    for (saved, pointer) in self.pointers.iter().enumerate() {
+                        let saved = args + self.locals + saved as u16;
+                        commands.push(VmCommand::PushStack(depth - saved));
+                        commands.push(VmCommand::Pop(Segment::Pointer, *pointer));
+                    }
+                    commands.push(VmCommand::Collapse(depth - 1));
+                    if idx + 1 == self.body.len() {
+                        continue;
+                    }
+                    jumps_to_end = true;
+                    VmCommand::Goto(end.clone())
+                }
+                command => command.clone(),
+            };
+            commands.push(command);
+        }
+        if jumps_to_end {
+            commands.push(VmCommand::Label(end));
+        }
+        Some(commands)
+    }
+}
+
+// Replaces calls of short functions by their body, returns the number of calls replaced.
+// Functions are inlined as written, calls in an inlined body stay calls. Bodies using
+// statics are only inlined into their own file.
+pub fn inline(files: &mut [(String, Parser)]) -> usize {
+    let mut functions: HashMap<String, Inlinable> = HashMap::new();
+    for (file_name, parser) in files.iter() {
+        let commands = parser.commands();
+        let starts = commands
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, command))| matches!(command, VmCommand::Function(..)));
+        for (start, (_, command)) in starts {
+            let VmCommand::Function(name, locals) = command else {
+                continue;
+            };
+            let body: Vec<VmCommand> = commands[start + 1..]
+                .iter()
+                .map(|(_, command)| command.clone())
+                .take_while(|command| !matches!(command, VmCommand::Function(..)))
+                .collect();
+            if let Some(function) = inlinable(name, file_name, *locals, &body) {
+                functions.insert(name.clone(), function);
+            }
+        }
+    }
+
+    let mut count = 0;
+    for (file_name, parser) in files.iter_mut() {
+        let mut inlined = Vec::new();
+        // Labels of the function being rewritten, which renamed labels must not hit.
+        let mut labels: Vec<String> = Vec::new();
+        for (idx, (line, command)) in parser.commands().iter().enumerate() {
+            if let VmCommand::Function(..) = command {
+                labels = parser.commands()[idx + 1..]
+                    .iter()
+                    .take_while(|(_, command)| !matches!(command, VmCommand::Function(..)))
+                    .filter_map(|(_, command)| match command {
+                        VmCommand::Label(label) => Some(label.clone()),
+                        _ => None,
+                    })
+                    .collect();
+            }
+            let expanded = match command {
+                VmCommand::Call(name, args) => functions.get(name).and_then(|function| {
+                    let suffix = format!("$inline.{}", count);
+                    let clash = function
+                        .labels
+                        .iter()
+                        .map(String::as_str)
+                        .chain(["END"])
+                        .any(|label| labels.contains(&format!("{}{}", label, suffix)));
+                    if clash || function.uses_statics && function.file_name != *file_name {
+                        return None;
+                    }
+                    function.expand(*args, &suffix)
+                }),
+                _ => None,
+            };
+            match expanded {
+                Some(commands) => {
+                    count += 1;
+                    inlined.extend(commands.into_iter().map(|command| (*line, command)));
+                }
+                None => inlined.push((*line, command.clone())),
+            }
+        }
+        parser.commands = inlined;
+    }
+    count
+}
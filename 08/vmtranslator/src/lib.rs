@@ -1,161 +1,687 @@
-use core::panic;
-use std::{error::Error, fs, io::Write, ops::Index};
-
-#[derive(PartialEq)]
-pub enum CommandType {
-    CArithmetic = 1,
-    CPush = 2,
-    CPop = 3,
-    CLabel = 4,
-    CGoto = 5,
-    CIf = 6,
-    CFunction = 7,
-    CReturn = 8,
-    CCall = 9,
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+pub mod inliner;
+pub mod optimizer;
+#[cfg(test)]
+mod tests;
+pub mod verifier;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Segment {
+    Argument,
+    Local,
+    Static,
+    Constant,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+impl Segment {
+    pub fn from_name(name: &str) -> Option<Segment> {
+        let segment = match name {
+            "argument" => Segment::Argument,
+            "local" => Segment::Local,
+            "static" => Segment::Static,
+            "constant" => Segment::Constant,
+            "this" => Segment::This,
+            "that" => Segment::That,
+            "pointer" => Segment::Pointer,
+            "temp" => Segment::Temp,
+            _ => return None,
+        };
+        Some(segment)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::Constant => "constant",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        }
+    }
+
+    // Largest valid index: pointer and temp are fixed RAM areas, the rest must fit into an
+    // A-instruction.
+    pub fn max_index(&self) -> u16 {
+        match self {
+            Segment::Pointer => 1,
+            Segment::Temp => 7,
+            _ => 32767,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+}
+
+impl ArithOp {
+    pub fn from_name(name: &str) -> Option<ArithOp> {
+        let op = match name {
+            "add" => ArithOp::Add,
+            "sub" => ArithOp::Sub,
+            "neg" => ArithOp::Neg,
+            "eq" => ArithOp::Eq,
+            "gt" => ArithOp::Gt,
+            "lt" => ArithOp::Lt,
+            "and" => ArithOp::And,
+            "or" => ArithOp::Or,
+            "not" => ArithOp::Not,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArithOp::Add => "add",
+            ArithOp::Sub => "sub",
+            ArithOp::Neg => "neg",
+            ArithOp::Eq => "eq",
+            ArithOp::Gt => "gt",
+            ArithOp::Lt => "lt",
+            ArithOp::And => "and",
+            ArithOp::Or => "or",
+            ArithOp::Not => "not",
+        }
+    }
+
+    pub fn is_unary(&self) -> bool {
+        *self == ArithOp::Neg || *self == ArithOp::Not
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum VmCommand {
+    Arithmetic(ArithOp),
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function(String, u16),
+    Call(String, u16),
+    Return,
+    // Produced by the optimizer, never parsed.
+    // `push a i; pop b j` as a direct memory move.
+    Move(Segment, u16, Segment, u16),
+    // `push constant 0; eq`: whether the top of the stack is zero.
+    IsZero,
+    // eq, gt or lt, negated by `not` if true, followed by `if-goto label`.
+    IfCompare(ArithOp, bool, String),
+    // Produced by the inliner for the arguments and locals of inlined functions, which live
+    // on the stack. Push or pop the value `k` below the top, e.g. 1 for the top.
+    PushStack(u16),
+    PopStack(u16),
+    // Drops the `k` values below the top.
+    Collapse(u16),
+}
+
+impl VmCommand {
+    // The number of values the command leaves on the stack minus those it takes.
+    pub fn stack_effect(&self) -> i32 {
+        match self {
+            VmCommand::Arithmetic(op) if op.is_unary() => 0,
+            VmCommand::Arithmetic(_) => -1,
+            VmCommand::Push(..) | VmCommand::PushStack(_) => 1,
+            VmCommand::Pop(..) | VmCommand::PopStack(_) | VmCommand::IfGoto(_) => -1,
+            VmCommand::Call(_, args) => 1 - *args as i32,
+            VmCommand::Return => -1,
+            VmCommand::IfCompare(..) => -2,
+            VmCommand::Collapse(k) => -(*k as i32),
+            VmCommand::Label(_)
+            | VmCommand::Goto(_)
+            | VmCommand::Function(..)
+            | VmCommand::Move(..)
+            | VmCommand::IsZero => 0,
+        }
+    }
+}
+
+impl fmt::Display for VmCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmCommand::Arithmetic(op) => write!(f, "{}", op.name()),
+            VmCommand::Push(segment, index) => write!(f, "push {} {}", segment.name(), index),
+            VmCommand::Pop(segment, index) => write!(f, "pop {} {}", segment.name(), index),
+            VmCommand::Label(label) => write!(f, "label {}", label),
+            VmCommand::Goto(label) => write!(f, "goto {}", label),
+            VmCommand::IfGoto(label) => write!(f, "if-goto {}", label),
+            VmCommand::Function(name, locals) => write!(f, "function {} {}", name, locals),
+            VmCommand::Call(name, args) => write!(f, "call {} {}", name, args),
+            VmCommand::Return => write!(f, "return"),
+            VmCommand::Move(from, i, to, j) => {
+                write!(f, "push {} {}; pop {} {}", from.name(), i, to.name(), j)
+            }
+            VmCommand::IsZero => write!(f, "push constant 0; eq"),
+            VmCommand::PushStack(k) => write!(f, "push stack {}", k),
+            VmCommand::PopStack(k) => write!(f, "pop stack {}", k),
+            VmCommand::Collapse(k) => write!(f, "collapse {}", k),
+            VmCommand::IfCompare(op, negate, label) => {
+                let not = if *negate { "not; " } else { "" };
+                write!(f, "{}; {}if-goto {}", op.name(), not, label)
+            }
+        }
+    }
+}
+
+// Labels and function names: letters, digits, '_', '.', '$' and ':', not starting with a digit.
+// `11/jackcompiler` also uses '-' in its labels, but not first, as the assembler reads `@-1` as
+// a bad constant.
+fn is_symbol(name: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_.$:-".contains(c);
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        && name.chars().all(valid)
+}
+
+// Parses one line without its comment, None for blank lines.
+pub fn parse_command(line: &str) -> Result<Option<VmCommand>, String> {
+    let line = match line.find("//") {
+        Some(idx) => &line[..idx],
+        None => line,
+    };
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+
+    let arg = |idx: usize, what: &str| -> Result<&str, String> {
+        words
+            .get(idx)
+            .copied()
+            .ok_or_else(|| format!("missing {} after '{}'", what, words[0]))
+    };
+    let symbol = |idx: usize, what: &str| -> Result<String, String> {
+        let name = arg(idx, what)?;
+        if !is_symbol(name) {
+            return Err(format!("invalid {} '{}'", what, name));
+        }
+        Ok(String::from(name))
+    };
+    let number = |idx: usize, what: &str| -> Result<u16, String> {
+        let text = arg(idx, what)?;
+        text.parse::<u16>()
+            .map_err(|_| format!("invalid {} '{}'", what, text))
+    };
+    let segment = || -> Result<(Segment, u16), String> {
+        let name = arg(1, "segment")?;
+        let segment =
+            Segment::from_name(name).ok_or_else(|| format!("unknown segment '{}'", name))?;
+        let index = number(2, "index")?;
+        if index > segment.max_index() {
+            return Err(format!(
+                "index {} is out of range for {} (0..{})",
+                index,
+                name,
+                segment.max_index()
+            ));
+        }
+        Ok((segment, index))
+    };
+
+    let (command, argc) = match words[0] {
+        "push" => {
+            let (segment, index) = segment()?;
+            (VmCommand::Push(segment, index), 3)
+        }
+        "pop" => {
+            let (segment, index) = segment()?;
+            if segment == Segment::Constant {
+                return Err(String::from("cannot pop to the constant segment"));
+            }
+            (VmCommand::Pop(segment, index), 3)
+        }
+        "label" => (VmCommand::Label(symbol(1, "label")?), 2),
+        "goto" => (VmCommand::Goto(symbol(1, "label")?), 2),
+        "if-goto" => (VmCommand::IfGoto(symbol(1, "label")?), 2),
+        "function" => (
+            VmCommand::Function(symbol(1, "function name")?, number(2, "local count")?),
+            3,
+        ),
+        "call" => (
+            VmCommand::Call(symbol(1, "function name")?, number(2, "argument count")?),
+            3,
+        ),
+        "return" => (VmCommand::Return, 1),
+        name => match ArithOp::from_name(name) {
+            Some(op) => (VmCommand::Arithmetic(op), 1),
+            None => return Err(format!("unknown command '{}'", name)),
+        },
+    };
+    if let Some(extra) = words.get(argc) {
+        return Err(format!("unexpected '{}' after '{}'", extra, command));
+    }
+    Ok(Some(command))
 }
 
 pub struct Config {
-    pub in_file: String,
+    // .vm files and directories of .vm files.
+    pub in_files: Vec<String>,
     pub out_file: String,
+    // Set SP and call Sys.init first, off for project 07 style tests that set SP themselves.
+    pub bootstrap: bool,
+    // Reject gotos to labels outside of the current function.
+    pub check_labels: bool,
+    // Share call, return and comparison code between all uses.
+    pub compact: bool,
+    // Comment every command with its source in the output.
+    pub annotate: bool,
+    // Write a JSON map from ROM addresses to VM commands next to the output.
+    pub source_map: bool,
+    // Also write the assembly next to a .hack output.
+    pub keep_asm: bool,
+    // Leave out functions that Sys.init never calls.
+    pub prune: bool,
+    // Rewrite common command sequences into shorter code.
+    pub optimize: bool,
+    // Keep the top of the stack in D.
+    pub cache_top: bool,
+    // Replace calls of short functions by their body.
+    pub inline: bool,
+    // Check stack depths, labels and calls of all files before translating.
+    pub verify: bool,
 }
 
 impl Config {
     pub fn build(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() != 3 {
+        let mut paths = Vec::new();
+        let mut check_labels = false;
+        let mut compact = false;
+        let mut bootstrap = true;
+        let mut annotate = false;
+        let mut source_map = false;
+        let mut keep_asm = false;
+        let mut prune = false;
+        let mut optimize = false;
+        let mut cache_top = false;
+        let mut inline = false;
+        let mut verify = false;
+        for arg in &args[1..] {
+            match arg.as_str() {
+                "--check-labels" => check_labels = true,
+                "--compact" => compact = true,
+                "--no-bootstrap" => bootstrap = false,
+                "--annotate" => annotate = true,
+                "--source-map" => source_map = true,
+                "--keep-asm" => keep_asm = true,
+                "--prune" => prune = true,
+                "--optimize" => optimize = true,
+                "--cache-top" => cache_top = true,
+                "--inline" => inline = true,
+                "--verify" => verify = true,
+                arg if arg.starts_with("--") => return Err("Unknown option!"),
+                _ => paths.push(arg.clone()),
+            }
+        }
+        if paths.len() < 2 {
             return Err("Not correct number of arguments!");
         }
 
-        let in_file = args[1].clone();
-        let out_file = args[2].clone();
+        // The output comes last, after one or more inputs.
+        let out_file = paths.pop().unwrap();
+        let out_path = Path::new(&out_file);
+        if out_path.is_dir() || out_path.extension().is_some_and(|ext| ext == "vm") {
+            return Err("The last argument must be the output .asm or .hack path!");
+        }
 
-        Ok(Config { in_file, out_file })
+        Ok(Config {
+            in_files: paths,
+            out_file,
+            bootstrap,
+            check_labels,
+            compact,
+            annotate,
+            source_map,
+            keep_asm,
+            prune,
+            optimize,
+            cache_top,
+            inline,
+            verify,
+        })
     }
 }
 
 pub struct Parser {
-    lines: Vec<String>,
-    current_command: Vec<String>,
-    current_line_idx: usize,
+    // Commands with their line numbers, parsed up front.
+    commands: Vec<(usize, VmCommand)>,
+    current_command_idx: usize,
 }
 impl Parser {
     pub fn build(in_file: &str) -> Result<Parser, Box<dyn Error>> {
-        let contents = fs::read_to_string(in_file)?;
-        let lines = contents.split("\n");
-        return Ok(Parser {
-            lines: lines
-                .into_iter()
-                .map(|line| line.trim())
-                .filter(|line| !line.starts_with("//") && !line.is_empty())
-                .map(|line| {
-                    let res = line.find("//");
-                    match res {
-                        Some(idx) => String::from(line.trim().split_at(idx).0),
-                        None => String::from(line.trim()),
-                    }
-                })
-                .collect(),
-            current_command: Vec::new(),
-            current_line_idx: 0,
-        });
+        let contents =
+            fs::read_to_string(in_file).map_err(|err| format!("{}: {}", in_file, err))?;
+        Self::parse(in_file, &contents)
+    }
+
+    // Parses every line, reporting all errors as `file:line: message`.
+    pub fn parse(file_name: &str, contents: &str) -> Result<Parser, Box<dyn Error>> {
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+        for (idx, line) in contents.lines().enumerate() {
+            match parse_command(line) {
+                Ok(Some(command)) => commands.push((idx + 1, command)),
+                Ok(None) => {}
+                Err(err) => errors.push(format!("{}:{}: {}", file_name, idx + 1, err)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.join("\n").into());
+        }
+        Ok(Parser {
+            commands,
+            current_command_idx: 0,
+        })
     }
 
     pub fn has_more_lines(&self) -> bool {
-        return self.current_line_idx < self.lines.len();
+        self.current_command_idx < self.commands.len()
     }
 
     pub fn advance(&mut self) {
-        self.current_command = String::from(self.lines.index(self.current_line_idx).as_str())
-            .split(" ")
-            .map(|line| String::from(line.trim()))
-            .collect();
+        self.current_command_idx += 1;
+    }
 
-        if self.current_command.is_empty() {
-            panic!("current command cannot be empty!")
-        }
-
-        self.current_line_idx += 1;
-    }
-
-    pub fn command_type(&self) -> CommandType {
-        return match self.current_command[0].as_str() {
-            "push" => CommandType::CPush,
-            "pop" => CommandType::CPop,
-            "add" => CommandType::CArithmetic,
-            "sub" => CommandType::CArithmetic,
-            "neg" => CommandType::CArithmetic,
-            "eq" => CommandType::CArithmetic,
-            "gt" => CommandType::CArithmetic,
-            "lt" => CommandType::CArithmetic,
-            "and" => CommandType::CArithmetic,
-            "or" => CommandType::CArithmetic,
-            "not" => CommandType::CArithmetic,
-            "label" => CommandType::CLabel,
-            "if-goto" => CommandType::CIf,
-            "goto" => CommandType::CGoto,
-            "function" => CommandType::CFunction,
-            "call" => CommandType::CCall,
-            "return" => CommandType::CReturn,
-            _ => panic!("invalid command type {}.", self.current_command[0].as_str()),
-        };
+    pub fn command(&self) -> &VmCommand {
+        &self.commands[self.current_command_idx - 1].1
+    }
+
+    pub fn line(&self) -> usize {
+        self.commands[self.current_command_idx - 1].0
+    }
+
+    // Replaces the commands by their optimized version, before the first `advance`.
+    pub fn optimize(&mut self) {
+        self.commands = optimizer::optimize(&self.commands);
+    }
+
+    pub fn commands(&self) -> &[(usize, VmCommand)] {
+        &self.commands
+    }
+}
+
+// Checks that labels are unique within their function and that every goto and if-goto
+// targets a label of its own function, which is all the VM specification allows.
+pub fn check_labels(
+    file_name: &str,
+    commands: &[(usize, VmCommand)],
+) -> Result<(), Box<dyn Error>> {
+    let mut errors = Vec::new();
+    // Commands are split into functions, code before the first function is its own scope.
+    let mut scopes: Vec<(&str, Vec<&(usize, VmCommand)>)> = vec![("", Vec::new())];
+    for command in commands {
+        if let VmCommand::Function(name, _) = &command.1 {
+            scopes.push((name, Vec::new()));
+        }
+        scopes.last_mut().unwrap().1.push(command);
+    }
+
+    for (function, commands) in scopes {
+        let mut labels: Vec<&str> = Vec::new();
+        for (line, command) in &commands {
+            if let VmCommand::Label(label) = command {
+                if labels.contains(&label.as_str()) {
+                    errors.push(format!("{}:{}: duplicate label {}", file_name, line, label));
+                }
+                labels.push(label);
+            }
+        }
+        for (line, command) in &commands {
+            let label = match command {
+                VmCommand::Goto(label) | VmCommand::IfGoto(label) => label,
+                _ => continue,
+            };
+            if !labels.contains(&label.as_str()) {
+                let scope = match function {
+                    "" => String::from("outside of functions"),
+                    function => format!("in function {}", function),
+                };
+                errors.push(format!(
+                    "{}:{}: no label {} {}",
+                    file_name, line, label, scope
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+    Ok(())
+}
+
+// The instructions `address..address + length` translate `command` from `file` and `line`.
+#[derive(Debug, PartialEq)]
+pub struct SourceEntry {
+    pub address: usize,
+    pub length: usize,
+    pub file: String,
+    pub line: usize,
+    pub function: String,
+    pub command: String,
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// A JSON array with an object per source entry, one per line.
+pub fn source_map_json(entries: &[SourceEntry]) -> String {
+    let objects: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "  {{\"address\": {}, \"length\": {}, \"file\": {}, \"line\": {}, \"function\": {}, \"command\": {}}}",
+                entry.address,
+                entry.length,
+                json_string(&entry.file),
+                entry.line,
+                json_string(&entry.function),
+                json_string(&entry.command)
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+// The functions called directly or indirectly from `root`, and `root` itself if it exists.
+pub fn reachable_functions<'a>(
+    files: impl IntoIterator<Item = &'a [(usize, VmCommand)]>,
+    root: &str,
+) -> HashSet<String> {
+    let mut calls: HashMap<&str, Vec<&str>> = HashMap::new();
+    // Code before the first function of a file always runs, so do its callees.
+    let mut pending = vec![root];
+    for commands in files {
+        let mut function = None;
+        for (_, command) in commands {
+            match command {
+                VmCommand::Function(name, _) => {
+                    calls.entry(name).or_default();
+                    function = Some(name.as_str());
+                }
+                VmCommand::Call(name, _) => match function {
+                    Some(function) => calls.entry(function).or_default().push(name),
+                    None => pending.push(name),
+                },
+                _ => {}
+            }
+        }
     }
 
-    pub fn arg1(&self) -> String {
-        let command_type = self.command_type();
-        if command_type == CommandType::CArithmetic {
-            return self.current_command[0].clone();
-        } else if command_type == CommandType::CReturn {
-            panic!("arg1() should not be called for command type creturn!")
+    let mut reachable = HashSet::new();
+    while let Some(function) = pending.pop() {
+        // Functions that are called but not defined have no code to keep.
+        let Some(callees) = calls.get(function) else {
+            continue;
+        };
+        if reachable.insert(function.to_string()) {
+            pending.extend(callees);
         }
-        return self.current_command[1].clone();
     }
-    pub fn arg2(&self) -> u16 {
-        let command_type = self.command_type();
-        if command_type != CommandType::CPush
-            && command_type != CommandType::CPop
-            && command_type != CommandType::CFunction
-            && command_type != CommandType::CCall
-            && command_type != CommandType::CLabel
-            && command_type != CommandType::CIf
-            && command_type != CommandType::CGoto
-        {
-            panic!("arg2 should not be called on operations other than: cpush, cpop, cfunction, ccall!")
+    reachable
+}
+
+// Code shared by all call sites with `--compact`, jumped to with the return address in D.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Routine {
+    Call,
+    Return,
+    // Comparison with its jump condition: JEQ, JGT or JLT.
+    Compare(&'static str),
+}
+
+impl Routine {
+    fn label(&self) -> &'static str {
+        match self {
+            Routine::Call => "VM$call",
+            Routine::Return => "VM$return",
+            Routine::Compare("JEQ") => "VM$eq",
+            Routine::Compare("JGT") => "VM$gt",
+            Routine::Compare(_) => "VM$lt",
         }
-        let arg: u16 = self.current_command[2].parse().unwrap();
-        return arg;
     }
 }
 
 pub struct CodeWriter {
-    file: fs::File,
-    current_line: u16,
+    output: String,
     current_file_name: String,
+    // Labels are scoped to the function they appear in.
+    current_function: String,
     call_count: u16,
+    // Comparisons jump over their false case to a label of their own.
+    compare_count: u16,
+    compact: bool,
+    // Shared routines jumped to so far.
+    routines: Vec<Routine>,
+    annotate: bool,
+    // Keep the top of the stack in D between commands, `top_in_d` says whether it is now.
+    cache_top: bool,
+    top_in_d: bool,
+    // ROM address of the next instruction, labels and comments take none.
+    rom_address: usize,
+    source_map: Vec<SourceEntry>,
+    // The command being written as `File.vm:line: 'command' in function`, if known.
+    source: Option<String>,
+    // Labels and functions written, and the symbols jumped to with the error if missing.
+    symbols: HashSet<String>,
+    targets: Vec<(String, String)>,
 }
 impl CodeWriter {
-    pub fn build(out_file: &str) -> CodeWriter {
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(out_file)
-            .unwrap();
-
-        return CodeWriter {
-            file,
-            current_line: 0,
+    pub fn build() -> CodeWriter {
+        CodeWriter {
+            output: String::new(),
             current_file_name: "".to_string(),
+            current_function: "".to_string(),
             call_count: 0,
-        };
+            compare_count: 0,
+            compact: false,
+            routines: Vec::new(),
+            annotate: false,
+            cache_top: false,
+            top_in_d: false,
+            rom_address: 0,
+            source_map: Vec::new(),
+            source: None,
+            symbols: HashSet::new(),
+            targets: Vec::new(),
+        }
     }
 
     pub fn set_file_name(&mut self, file_name: &str) {
         self.current_file_name = file_name.to_string();
-        // println!("Using filename: {}", file_name);
+    }
+
+    // Emit call, return and comparisons as jumps into shared routines, which
+    // `write_routines` adds at the end. Smaller code for a few more cycles per call.
+    pub fn set_compact(&mut self, compact: bool) {
+        self.compact = compact;
+    }
+
+    // Hold the top of the stack in D instead of RAM where the next command can use it from
+    // there. It is spilled to RAM before labels, jumps, calls and returns.
+    pub fn set_cache_top(&mut self, cache_top: bool) {
+        self.cache_top = cache_top;
+    }
+
+    // Precede the code of every command with the command and its source as a comment.
+    pub fn set_annotate(&mut self, annotate: bool) {
+        self.annotate = annotate;
+    }
+
+    // The assembly written so far.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    // The ROM ranges of the commands written with `write_source_command`.
+    pub fn source_map(&self) -> &[SourceEntry] {
+        &self.source_map
     }
 
     fn write_line(&mut self, line: &str) {
-        self.file.write(line.as_bytes()).unwrap();
-        self.current_line += 1;
+        if !line.starts_with('(') && !line.starts_with("//") {
+            self.rom_address += 1;
+        }
+        self.output.push_str(line);
+    }
+
+    // Writes a command from line `line` of the current file, recording where its code went.
+    pub fn write_source_command(&mut self, command: &VmCommand, line: usize) {
+        let file = format!("{}.vm", self.current_file_name);
+        if self.annotate {
+            self.write_line(format!("// {}  [{}:{}]\n", command, file, line).as_str());
+        }
+        let address = self.rom_address;
+        self.source = Some(format!(
+            "{}:{}: '{}' in {}",
+            file, line, command, self.current_function
+        ));
+        self.write_command(command);
+        self.source = None;
+        // Labels have no code to step through.
+        if self.rom_address > address {
+            self.source_map.push(SourceEntry {
+                address,
+                length: self.rom_address - address,
+                file,
+                line,
+                function: self.current_function.clone(),
+                command: command.to_string(),
+            });
+        }
     }
 
     pub fn write_init(&mut self) {
@@ -166,208 +692,491 @@ impl CodeWriter {
         self.write_call("Sys.init", 0);
     }
 
-    pub fn write_arithmetic(&mut self, command: &str) {
-        // SP--
-        self.write_line("@SP\n");
-        self.write_line("AM=M-1\n");
+    pub fn write_command(&mut self, command: &VmCommand) {
+        if self.cache_top && self.write_cached(command) {
+            return;
+        }
+        self.write_spill();
+
+        match command {
+            VmCommand::Arithmetic(op) => self.write_arithmetic(*op),
+            VmCommand::Push(segment, index) => self.write_push(*segment, *index),
+            VmCommand::Pop(segment, index) => self.write_pop(*segment, *index),
+            VmCommand::Label(label) => self.write_label(label),
+            VmCommand::Goto(label) => self.write_goto(label),
+            VmCommand::IfGoto(label) => self.write_if(label),
+            VmCommand::Function(name, locals) => self.write_function(name, *locals),
+            VmCommand::Call(name, args) => self.write_call(name, *args),
+            VmCommand::Return => self.write_return(),
+            VmCommand::Move(from, i, to, j) => self.write_move(*from, *i, *to, *j),
+            VmCommand::IsZero => self.write_is_zero(),
+            VmCommand::IfCompare(op, negate, label) => self.write_if_compare(*op, *negate, label),
+            VmCommand::PushStack(k) => self.write_push_stack(*k),
+            VmCommand::PopStack(k) => self.write_pop_stack(*k),
+            VmCommand::Collapse(k) => self.write_collapse(*k),
+        }
+    }
 
-        // D = RAM[SP]
-        self.write_line("D=M\n");
+    // Writes the commands that take or leave the top of the stack in D, returns false for the
+    // others which need it in RAM.
+    fn write_cached(&mut self, command: &VmCommand) -> bool {
+        match command {
+            VmCommand::Push(segment, index) => {
+                self.write_spill();
+                self.write_load(*segment, *index);
+                self.top_in_d = true;
+            }
+            VmCommand::Pop(segment, index) if self.top_in_d => {
+                self.write_store(*segment, *index);
+                self.top_in_d = false;
+            }
+            VmCommand::Arithmetic(op) => {
+                self.write_fill();
+                match op {
+                    ArithOp::Neg => self.write_line("D=-D\n"),
+                    ArithOp::Not => self.write_line("D=!D\n"),
+                    op => {
+                        // D = RAM[SP-1] op D, with SP--
+                        self.write_line("@SP\n");
+                        self.write_line("AM=M-1\n");
+                        match op {
+                            ArithOp::Add => self.write_line("D=D+M\n"),
+                            ArithOp::And => self.write_line("D=D&M\n"),
+                            ArithOp::Or => self.write_line("D=D|M\n"),
+                            ArithOp::Sub => self.write_line("D=M-D\n"),
+                            op => {
+                                self.write_line("D=M-D\n");
+                                self.write_d_is(Self::compare_jump(*op, false));
+                            }
+                        }
+                    }
+                }
+            }
+            VmCommand::IsZero => {
+                self.write_fill();
+                self.write_d_is("JEQ");
+            }
+            VmCommand::IfGoto(label) => {
+                self.write_fill();
+                let target = self.jump_target(label);
+                self.write_line(format!("@{}\n", target).as_str());
+                self.write_line("D;JNE\n");
+                self.top_in_d = false;
+            }
+            VmCommand::IfCompare(op, negate, label) => {
+                // D = RAM[SP-1] - D, with SP--
+                self.write_fill();
+                self.write_line("@SP\n");
+                self.write_line("AM=M-1\n");
+                self.write_line("D=M-D\n");
+                let target = self.jump_target(label);
+                self.write_line(format!("@{}\n", target).as_str());
+                self.write_line(format!("D;{}\n", Self::compare_jump(*op, *negate)).as_str());
+                self.top_in_d = false;
+            }
+            _ => return false,
+        }
+        true
+    }
 
-        if command == "neg" || command == "not" {
-            let cmd = match command {
-                "neg" => "-",
-                "not" => "!",
-                _ => panic!("not implemented"),
-            };
-            // RAM[SP] = -/! RAM[SP]
-            self.write_line(format!("M={}M\n", cmd).as_str());
-        } else {
+    // Moves the top of the stack from D to RAM.
+    fn write_spill(&mut self) {
+        if self.top_in_d {
+            self.write_push_d();
+            self.top_in_d = false;
+        }
+    }
+
+    // Moves the top of the stack from RAM to D.
+    fn write_fill(&mut self) {
+        if !self.top_in_d {
+            self.write_line("@SP\n");
+            self.write_line("AM=M-1\n");
+            self.write_line("D=M\n");
+            self.top_in_d = true;
+        }
+    }
+
+    // D = true if D JEQ/JGT/... 0, else false.
+    fn write_d_is(&mut self, jump: &str) {
+        let label = format!("{}$cmp.{}", self.current_file_name, self.compare_count);
+        self.compare_count += 1;
+        self.write_line(format!("@{}\n", label).as_str());
+        self.write_line(format!("D;{}\n", jump).as_str());
+        self.write_line("D=0\n");
+        self.write_line(format!("@{}.end\n", label).as_str());
+        self.write_line("0;JMP\n");
+        self.write_symbol(&label);
+        self.write_line("D=-1\n");
+        self.write_symbol(&format!("{}.end", label));
+    }
+
+    pub fn write_arithmetic(&mut self, op: ArithOp) {
+        let jump = match op {
+            ArithOp::Eq => "JEQ",
+            ArithOp::Gt => "JGT",
+            ArithOp::Lt => "JLT",
+            _ => "",
+        };
+        if !jump.is_empty() {
+            let label = format!("{}$cmp.{}", self.current_file_name, self.compare_count);
+            self.compare_count += 1;
+            if self.compact {
+                // D = return address
+                self.write_line(format!("@{}\n", label).as_str());
+                self.write_line("D=A\n");
+                self.write_routine_jump(Routine::Compare(jump));
+                self.write_symbol(&label);
+            } else {
+                self.write_comparison(jump, &label);
+            }
+            return;
+        }
+
+        if op.is_unary() {
             // SP--
             self.write_line("@SP\n");
             self.write_line("AM=M-1\n");
 
-            if command == "add" || command == "sub" {
-                let cmd = match command {
-                    "add" => "+",
-                    "sub" => "-",
-                    _ => panic!("not implemented"),
-                };
+            let cmd = if op == ArithOp::Neg { "-" } else { "!" };
+            // RAM[SP] = -/! RAM[SP]
+            self.write_line(format!("M={}M\n", cmd).as_str());
+        } else {
+            self.write_pop_operands();
 
-                // RAM[SP] = RAM[SP] +/- D
-                self.write_line(format!("M=M{}D\n", cmd).as_str());
-            } else if command == "and" || command == "or" {
-                let cmd = match command {
-                    "and" => "&",
-                    "or" => "|",
-                    _ => panic!("not implemented"),
-                };
+            let cmd = match op {
+                ArithOp::Add => "+",
+                ArithOp::Sub => "-",
+                ArithOp::And => "&",
+                _ => "|",
+            };
 
-                // RAM[SP] = RAM[SP] &/| D
-                self.write_line(format!("M=M{}D\n", cmd).as_str());
-            } else if command == "eq" || command == "gt" || command == "lt" {
-                let cmd = match command {
-                    "eq" => "JEQ",
-                    "gt" => "JGT",
-                    "lt" => "JLT",
-                    _ => panic!("not implemented"),
-                };
+            // RAM[SP] = RAM[SP] +/-/&/| D
+            self.write_line(format!("M=M{}D\n", cmd).as_str());
+        }
 
-                // RAM[SP] = RAM[SP] - D
-                self.write_line("D=M-D\n");
+        // SP++
+        self.write_line("@SP\n");
+        self.write_line("M=M+1\n");
+    }
 
-                // @line_true, RAM[SP];JEQ/JGT/JLT
-                self.write_line(format!("@{}\n", self.current_line + 7).as_str());
-                self.write_line(format!("D;{}\n", cmd).as_str());
+    // Pops two values and pushes true if their difference passes the jump condition.
+    fn write_comparison(&mut self, jump: &'static str, label: &str) {
+        self.write_pop_operands();
 
-                // RAM[SP] = 0, @line_skip_true, jump
-                self.write_line("@SP\n");
-                self.write_line("A=M\n");
-                self.write_line("M=0\n");
-                self.write_line(format!("@{}\n", self.current_line + 5).as_str());
-                self.write_line("0;JMP\n");
+        // D = RAM[SP] - D, RAM[SP] = true
+        self.write_line("D=M-D\n");
+        self.write_line("M=-1\n");
 
-                // RAM[SP] = -1
-                self.write_line("@SP\n");
-                self.write_line("A=M\n");
-                self.write_line("M=-1\n");
-            }
-        }
+        // Keep true if D JEQ/JGT/JLT 0, else RAM[SP] = false
+        self.write_line(format!("@{}\n", label).as_str());
+        self.write_line(format!("D;{}\n", jump).as_str());
+        self.write_line("@SP\n");
+        self.write_line("A=M\n");
+        self.write_line("M=0\n");
+        self.write_symbol(label);
 
         // SP++
         self.write_line("@SP\n");
         self.write_line("M=M+1\n");
     }
 
-    pub fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: u16) {
-        let segment_pointer = match segment {
-            "constant" => "constant",
-            "local" => "LCL",
-            "argument" => "ARG",
-            "this" => "THIS",
-            "that" => "THAT",
-            "temp" => "temp",
-            "static" => "static",
-            "pointer" => "pointer",
-            _ => panic!("unknown segment label"),
-        };
-        if command_type == CommandType::CPush {
-            if segment_pointer == "constant" {
-                // D=i
-                self.write_line(format!("@{}\n", index).as_str());
-                self.write_line("D=A\n");
+    // D = the top of the stack, A = the address of the value below it, SP at that value.
+    fn write_pop_operands(&mut self) {
+        // SP--
+        self.write_line("@SP\n");
+        self.write_line("AM=M-1\n");
 
-                // RAM[SP] = D
-                self.write_line("@SP\n");
-                self.write_line("A=M\n");
-                self.write_line("M=D\n");
-            } else {
-                if segment_pointer == "static" {
-                    self.write_line(format!("@{}.{}\n", self.current_file_name, index).as_str());
-                    self.write_line("D=M\n");
-                } else if segment_pointer == "temp"
-                    || segment_pointer == "pointer"
-                {
-                    let segment = match segment_pointer {
-                        "temp" => format!("{}", 5 + index),
-                        "pointer" => {
-                            if index == 0 {
-                                String::from("THIS")
-                            } else {
-                                String::from("THAT")
-                            }
-                        }
-                        _ => panic!("should not happen!"),
-                    };
-
-                    // D <- (segment address)
-                    self.write_line(format!("@{}\n", segment).as_str());
-                    self.write_line("D=M\n");
-                } else {
-                    // D <- segmentPointer + i
-                    self.write_line(format!("@{}\n", index).as_str());
-                    self.write_line("D=A\n");
-
-                    // @segmentPointer
-                    self.write_line(format!("@{}\n", segment_pointer).as_str());
-
-                    // D <- M[segmentPointer + i]
-                    self.write_line("A=M+D\n");
-                    self.write_line("D=M\n");
-                }
+        // D = RAM[SP]
+        self.write_line("D=M\n");
 
-                // RAM[SP] <- RAM[D]
-                self.write_line("@SP\n");
-                self.write_line("A=M\n");
-                self.write_line("M=D\n");
-            }
-            // SP++
-            self.write_line("@SP\n");
-            self.write_line("M=M+1\n");
-        } else if command_type == CommandType::CPop {
-            if segment_pointer == "static" {
-                self.write_line(format!("@{}.{}\n", self.current_file_name, index).as_str());
-                self.write_line("D=A\n"); // TODO: D=A?
-                self.write_line("@R13\n");
-                self.write_line("M=D\n");
-            } else if segment_pointer == "temp"
-                || segment_pointer == "pointer"
-            {
-                // addr <- temp/static + i
-                let segment = match segment_pointer {
-                    "temp" => format!("{}", 5 + index),
-                    "pointer" => {
-                        if index == 0 {
-                            String::from("THIS")
-                        } else {
-                            String::from("THAT")
-                        }
-                    }
-                    _ => panic!("should not happen!"),
-                };
+        // SP--
+        self.write_line("@SP\n");
+        self.write_line("AM=M-1\n");
+    }
 
-                // addr <- temp/static + i
-                self.write_line(format!("@{}\n", segment).as_str());
-                self.write_line("D=A\n");
-                self.write_line("@R13\n");
-                self.write_line("M=D\n");
-            } else {
-                // addr <- segmentPointer + i
-                self.write_line(format!("@{}\n", index).as_str());
-                self.write_line("D=A\n");
+    // Symbol of a fixed-address segment entry: static variable, temp or pointer register.
+    fn fixed_address(&self, segment: Segment, index: u16) -> Option<String> {
+        match segment {
+            Segment::Static => Some(format!("{}.{}", self.current_file_name, index)),
+            Segment::Temp => Some(format!("{}", 5 + index)),
+            Segment::Pointer if index == 0 => Some(String::from("THIS")),
+            Segment::Pointer => Some(String::from("THAT")),
+            _ => None,
+        }
+    }
 
-                // @segmentPointer
-                self.write_line(format!("@{}\n", segment_pointer).as_str());
+    // Base pointer of a segment addressed through memory.
+    fn segment_pointer(segment: Segment) -> &'static str {
+        match segment {
+            Segment::Local => "LCL",
+            Segment::Argument => "ARG",
+            Segment::This => "THIS",
+            _ => "THAT",
+        }
+    }
 
-                // addr <- M + D
-                self.write_line("D=M+D\n");
-                self.write_line("@R13\n");
-                self.write_line("M=D\n");
-            }
+    pub fn write_push(&mut self, segment: Segment, index: u16) {
+        self.write_load(segment, index);
 
-            // SP--
-            self.write_line("@SP\n");
-            self.write_line("AM=M-1\n");
+        // RAM[SP] <- D
+        self.write_push_d();
+    }
+
+    // D <- the value at segment index.
+    fn write_load(&mut self, segment: Segment, index: u16) {
+        if segment == Segment::Constant {
+            // D=i
+            self.write_line(format!("@{}\n", index).as_str());
+            self.write_line("D=A\n");
+        } else if let Some(address) = self.fixed_address(segment, index) {
+            // D <- (segment address)
+            self.write_line(format!("@{}\n", address).as_str());
+            self.write_line("D=M\n");
+        } else {
+            // D <- segmentPointer + i
+            self.write_line(format!("@{}\n", index).as_str());
+            self.write_line("D=A\n");
+
+            // @segmentPointer
+            self.write_line(format!("@{}\n", Self::segment_pointer(segment)).as_str());
+
+            // D <- M[segmentPointer + i]
+            self.write_line("A=M+D\n");
+            self.write_line("D=M\n");
+        }
+    }
+
+    // segment index <- D
+    fn write_store(&mut self, segment: Segment, index: u16) {
+        if let Some(address) = self.fixed_address(segment, index) {
+            self.write_line(format!("@{}\n", address).as_str());
+            self.write_line("M=D\n");
+        } else if index <= 5 {
+            // Short offsets are cheaper to step to than to add.
+            self.write_line(format!("@{}\n", Self::segment_pointer(segment)).as_str());
+            self.write_line("A=M\n");
+            for _ in 0..index {
+                self.write_line("A=A+1\n");
+            }
+            self.write_line("M=D\n");
+        } else {
+            // R13 = D, R14 = segmentPointer + index
+            self.write_line("@R13\n");
+            self.write_line("M=D\n");
+            self.write_line(format!("@{}\n", index).as_str());
+            self.write_line("D=A\n");
+            self.write_line(format!("@{}\n", Self::segment_pointer(segment)).as_str());
+            self.write_line("D=M+D\n");
+            self.write_line("@R14\n");
+            self.write_line("M=D\n");
 
-            // RAM[addr] <- RAM[SP]
+            self.write_line("@R13\n");
             self.write_line("D=M\n");
+            self.write_line("@R14\n");
+            self.write_line("A=M\n");
+            self.write_line("M=D\n");
+        }
+    }
+
+    pub fn write_move(&mut self, from: Segment, i: u16, to: Segment, j: u16) {
+        if self.fixed_address(to, j).is_some() || j <= 5 {
+            self.write_load(from, i);
+            self.write_store(to, j);
+        } else {
+            // R13 <- segmentPointer + j
+            self.write_line(format!("@{}\n", j).as_str());
+            self.write_line("D=A\n");
+            self.write_line(format!("@{}\n", Self::segment_pointer(to)).as_str());
+            self.write_line("D=M+D\n");
+            self.write_line("@R13\n");
+            self.write_line("M=D\n");
+
+            self.write_load(from, i);
             self.write_line("@R13\n");
             self.write_line("A=M\n");
             self.write_line("M=D\n");
         }
     }
 
+    pub fn write_push_stack(&mut self, k: u16) {
+        // D = RAM[SP - k]
+        self.write_line(format!("@{}\n", k).as_str());
+        self.write_line("D=A\n");
+        self.write_line("@SP\n");
+        self.write_line("A=M-D\n");
+        self.write_line("D=M\n");
+        self.write_push_d();
+    }
+
+    pub fn write_pop_stack(&mut self, k: u16) {
+        // R13 = SP - k
+        self.write_line(format!("@{}\n", k).as_str());
+        self.write_line("D=A\n");
+        self.write_line("@SP\n");
+        self.write_line("D=M-D\n");
+        self.write_line("@R13\n");
+        self.write_line("M=D\n");
+
+        // SP--, RAM[R13] = RAM[SP]
+        self.write_line("@SP\n");
+        self.write_line("AM=M-1\n");
+        self.write_line("D=M\n");
+        self.write_line("@R13\n");
+        self.write_line("A=M\n");
+        self.write_line("M=D\n");
+    }
+
+    pub fn write_collapse(&mut self, k: u16) {
+        if k == 0 {
+            return;
+        }
+        // R13 = top, SP -= k + 1
+        self.write_line("@SP\n");
+        self.write_line("AM=M-1\n");
+        self.write_line("D=M\n");
+        self.write_line("@R13\n");
+        self.write_line("M=D\n");
+        self.write_line(format!("@{}\n", k).as_str());
+        self.write_line("D=A\n");
+        self.write_line("@SP\n");
+        self.write_line("M=M-D\n");
+
+        // push R13
+        self.write_line("@R13\n");
+        self.write_line("D=M\n");
+        self.write_push_d();
+    }
+
+    pub fn write_is_zero(&mut self) {
+        let label = format!("{}$cmp.{}", self.current_file_name, self.compare_count);
+        self.compare_count += 1;
+
+        // D = RAM[SP-1], RAM[SP-1] = true
+        self.write_line("@SP\n");
+        self.write_line("A=M-1\n");
+        self.write_line("D=M\n");
+        self.write_line("M=-1\n");
+
+        // Keep true if D is 0, else RAM[SP-1] = false
+        self.write_line(format!("@{}\n", label).as_str());
+        self.write_line("D;JEQ\n");
+        self.write_line("@SP\n");
+        self.write_line("A=M-1\n");
+        self.write_line("M=0\n");
+        self.write_symbol(&label);
+    }
+
+    pub fn write_pop(&mut self, segment: Segment, index: u16) {
+        if let Some(address) = self.fixed_address(segment, index) {
+            // addr <- temp/static/pointer + i
+            self.write_line(format!("@{}\n", address).as_str());
+            self.write_line("D=A\n");
+        } else {
+            // addr <- segmentPointer + i
+            self.write_line(format!("@{}\n", index).as_str());
+            self.write_line("D=A\n");
+
+            // @segmentPointer
+            self.write_line(format!("@{}\n", Self::segment_pointer(segment)).as_str());
+
+            // addr <- M + D
+            self.write_line("D=M+D\n");
+        }
+        self.write_line("@R13\n");
+        self.write_line("M=D\n");
+
+        // SP--
+        self.write_line("@SP\n");
+        self.write_line("AM=M-1\n");
+
+        // RAM[addr] <- RAM[SP]
+        self.write_line("D=M\n");
+        self.write_line("@R13\n");
+        self.write_line("A=M\n");
+        self.write_line("M=D\n");
+    }
+
+    // `Function$label` inside functions, as the VM specification requires.
+    fn scoped_label(&self, label: &str) -> String {
+        match self.current_function.as_str() {
+            "" => String::from(label),
+            function => format!("{}${}", function, label),
+        }
+    }
+
+    // The symbol of a jump to `label`, remembered to check that the label exists.
+    fn jump_target(&mut self, label: &str) -> String {
+        let symbol = self.scoped_label(label);
+        let scope = match self.current_function.as_str() {
+            "" => String::from("outside of functions"),
+            function => format!("in function {}", function),
+        };
+        self.add_target(&symbol, format!("no label {} {}", label, scope));
+        symbol
+    }
+
+    fn add_target(&mut self, symbol: &str, message: String) {
+        let message = match &self.source {
+            Some(source) => format!("{}: {}", source, message),
+            None => message,
+        };
+        self.targets.push((symbol.to_string(), message));
+    }
+
+    // Jumps and calls to symbols no label or function defines, which the assembler would
+    // take for variables.
+    pub fn undefined_targets(&self) -> Vec<String> {
+        self.targets
+            .iter()
+            .filter(|(symbol, _)| !self.symbols.contains(symbol))
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
     pub fn write_label(&mut self, label: &str) {
-        self.write_line(format!("({})\n", label).as_str());
-        self.current_line -= 1;
+        let label = self.scoped_label(label);
+        self.write_symbol(&label);
+    }
 
+    fn write_symbol(&mut self, symbol: &str) {
+        self.symbols.insert(symbol.to_string());
+        self.write_line(format!("({})\n", symbol).as_str());
     }
 
     pub fn write_goto(&mut self, label: &str) {
-        self.write_line(format!("@{}\n", label).as_str());
+        let label = self.jump_target(label);
+        self.write_jump(&label);
+    }
+
+    fn write_jump(&mut self, symbol: &str) {
+        self.write_line(format!("@{}\n", symbol).as_str());
         self.write_line("0;JMP\n");
     }
 
+    // Jumps on the comparison of the top two values without pushing its result.
+    pub fn write_if_compare(&mut self, op: ArithOp, negate: bool, label: &str) {
+        self.write_pop_operands();
+
+        // D = RAM[SP] - D, jump if D compares to 0 as the negated or plain op
+        self.write_line("D=M-D\n");
+        let target = self.jump_target(label);
+        self.write_line(format!("@{}\n", target).as_str());
+        self.write_line(format!("D;{}\n", Self::compare_jump(op, negate)).as_str());
+    }
+
+    // The jump on x - y for eq, gt or lt of x and y, or for their negation.
+    fn compare_jump(op: ArithOp, negate: bool) -> &'static str {
+        match (op, negate) {
+            (ArithOp::Eq, false) => "JEQ",
+            (ArithOp::Eq, true) => "JNE",
+            (ArithOp::Gt, false) => "JGT",
+            (ArithOp::Gt, true) => "JLE",
+            (ArithOp::Lt, false) => "JLT",
+            (_, _) => "JGE",
+        }
+    }
+
     pub fn write_if(&mut self, label: &str) {
         // SP--
         self.write_line("@SP\n");
@@ -375,44 +1184,78 @@ impl CodeWriter {
                                      // D = RAM[SP]
         self.write_line("D=M\n");
         // if D != 0 then jump
-        self.write_line(format!("@{}\n", label).as_str());
+        let target = self.jump_target(label);
+        self.write_line(format!("@{}\n", target).as_str());
         self.write_line("D;JNE\n");
     }
 
-    pub fn write_function(&mut self, function_name: &str, num_args: u16) {
-        self.write_label(function_name);
+    pub fn write_function(&mut self, function_name: &str, num_locals: u16) {
+        self.current_function = function_name.to_string();
+        self.write_symbol(function_name);
 
-        for _ in 0..num_args {
-            self.write_push_pop(CommandType::CPush, "constant", 0);
+        for _ in 0..num_locals {
+            self.write_push(Segment::Constant, 0);
         }
     }
 
     pub fn write_call(&mut self, function_name: &str, num_args: u16) {
-        // Define label for return address (e.g. Foo$ret.1)
-        let label = format!("{}$ret.{}", function_name, self.call_count);
+        // Define label for return address, `Caller$ret.i` like in the VM specification (e.g.
+        // Main.main$ret.1), `VM$ret.i` for the bootstrap
+        let caller = match self.current_function.as_str() {
+            "" => "VM",
+            function => function,
+        };
+        let label = format!("{}$ret.{}", caller, self.call_count);
         self.call_count += 1;
+        self.add_target(function_name, format!("no function {}", function_name));
 
-        let push_d_to_stack = |writer: &mut CodeWriter| {
-            writer.write_line("@SP\n");
-            writer.write_line("A=M\n");
-            writer.write_line("M=D\n");
-            // Increment stack
-            writer.write_line("@SP\n");
-            writer.write_line("M=M+1\n");
-        };
+        if self.compact {
+            // R13 = num_args, R14 = function, D = return address
+            self.write_line(format!("@{}\n", num_args).as_str());
+            self.write_line("D=A\n");
+            self.write_line("@R13\n");
+            self.write_line("M=D\n");
+            self.write_line(format!("@{}\n", function_name).as_str());
+            self.write_line("D=A\n");
+            self.write_line("@R14\n");
+            self.write_line("M=D\n");
+            self.write_line(format!("@{}\n", label).as_str());
+            self.write_line("D=A\n");
+            self.write_routine_jump(Routine::Call);
+        } else {
+            // Push return address
+            self.write_line(format!("@{}\n", label).as_str());
+            self.write_line("D=A\n");
+            self.write_frame(Some(num_args));
 
-        // Push return address
-        self.write_line(format!("@{}\n", label).as_str());
-        self.write_line("D=A\n");
-        push_d_to_stack(self);
+            // Goto function
+            self.write_jump(function_name);
+        }
+
+        // Define label
+        self.write_symbol(label.as_str());
+    }
+
+    fn write_push_d(&mut self) {
+        self.write_line("@SP\n");
+        self.write_line("A=M\n");
+        self.write_line("M=D\n");
+        // Increment stack
+        self.write_line("@SP\n");
+        self.write_line("M=M+1\n");
+    }
+
+    // Pushes the return address in D and the caller's pointers, then points LCL and ARG at
+    // the new frame. The argument count is in R13 when not known.
+    fn write_frame(&mut self, num_args: Option<u16>) {
+        self.write_push_d();
 
         // Push pointers
-        let mut save_pointers = vec!["THAT", "THIS", "ARG", "LCL"];
-        while let Some(pointer) = save_pointers.pop() {
+        for pointer in ["LCL", "ARG", "THIS", "THAT"] {
             // Push pointer
             self.write_line(format!("@{}\n", pointer).as_str());
             self.write_line("D=M\n");
-            push_d_to_stack(self);
+            self.write_push_d();
         }
 
         // LCL = SP
@@ -423,19 +1266,31 @@ impl CodeWriter {
 
         // Reposition ARG pointer
         // D = SP; D -= 5 + num_args; ARG = D
-        self.write_line(format!("@{}\n", num_args + 5).as_str());
-        self.write_line("D=D-A\n");
+        match num_args {
+            Some(num_args) => {
+                self.write_line(format!("@{}\n", num_args + 5).as_str());
+                self.write_line("D=D-A\n");
+            }
+            None => {
+                self.write_line("@R13\n");
+                self.write_line("D=D-M\n");
+                self.write_line("@5\n");
+                self.write_line("D=D-A\n");
+            }
+        }
         self.write_line("@ARG\n");
         self.write_line("M=D\n");
-
-        // Goto function
-        self.write_goto(function_name);
-
-        // Define label
-        self.write_label(label.as_str());
     }
 
     pub fn write_return(&mut self) {
+        if self.compact {
+            self.write_routine_jump(Routine::Return);
+        } else {
+            self.write_return_sequence();
+        }
+    }
+
+    fn write_return_sequence(&mut self) {
         // endFrame = R13
         // retAddr = R14
 
@@ -466,18 +1321,15 @@ impl CodeWriter {
         self.write_line("@SP\n");
         self.write_line("M=D+1\n");
 
-        let pointers = vec!["THAT", "THIS", "ARG", "LCL"];
-        let mut i = 1;
-        while i < 5 {
-            // SEGMENT = *(endFrame - 1)
-            self.write_line(format!("@{}\n", i).as_str());
+        for (i, pointer) in ["THAT", "THIS", "ARG", "LCL"].iter().enumerate() {
+            // SEGMENT = *(endFrame - i)
+            self.write_line(format!("@{}\n", i + 1).as_str());
             self.write_line("D=A\n");
             self.write_line("@R13\n");
             self.write_line("A=M-D\n");
             self.write_line("D=M\n");
-            self.write_line(format!("@{}\n", pointers[i - 1]).as_str());
+            self.write_line(format!("@{}\n", pointer).as_str());
             self.write_line("M=D\n");
-            i += 1
         }
 
         // Jump to return address
@@ -485,66 +1337,224 @@ impl CodeWriter {
         self.write_line("A=M\n");
         self.write_line("0;JMP\n");
     }
-}
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut code_writer = CodeWriter::build(config.out_file.as_str());
-    let is_file = config.in_file.contains(".vm");
-    let mut input_files: Vec<String> = vec![];
+    fn write_routine_jump(&mut self, routine: Routine) {
+        if !self.routines.contains(&routine) {
+            self.routines.push(routine);
+        }
+        self.write_jump(routine.label());
+    }
 
-    if !is_file {
-        for entry in fs::read_dir(config.in_file)? {
-            let entry = entry?;
-            let file_name = String::from(entry.path().to_str().unwrap());
+    // Emits the shared routines used with `compact`, after a loop that stops programs
+    // running off their end into them.
+    pub fn write_routines(&mut self) {
+        // Programs that run off their end leave the whole stack in RAM.
+        self.write_spill();
+        if self.routines.is_empty() {
+            return;
+        }
+        self.write_symbol("VM$end");
+        self.write_jump("VM$end");
+
+        let mut routines = self.routines.clone();
+        routines.sort_by_key(|routine| routine.label());
+        for routine in routines {
+            self.write_symbol(routine.label());
+            match routine {
+                Routine::Call => {
+                    self.write_frame(None);
+                    self.write_line("@R14\n");
+                    self.write_line("A=M\n");
+                    self.write_line("0;JMP\n");
+                }
+                Routine::Return => self.write_return_sequence(),
+                Routine::Compare(jump) => {
+                    // R15 = return address
+                    self.write_line("@R15\n");
+                    self.write_line("M=D\n");
+                    let label = format!("{}.true", routine.label());
+                    self.write_comparison(jump, &label);
+                    self.write_line("@R15\n");
+                    self.write_line("A=M\n");
+                    self.write_line("0;JMP\n");
+                }
+            }
+        }
+    }
+}
 
-            if file_name.contains(".vm") {
-                input_files.push(file_name.to_string());
+// Expands directories into their .vm files and orders the files deterministically: Sys.vm
+// first, then in the order given with each directory sorted by name.
+pub fn input_files(paths: &[String]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let is_vm = |path: &Path| path.extension().is_some_and(|ext| ext == "vm");
+    let mut files: Vec<PathBuf> = Vec::new();
+    for path in paths.iter().map(Path::new) {
+        if path.is_dir() {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(path).map_err(|err| format!("{}: {}", path.display(), err))? {
+                let entry = entry?.path();
+                if entry.is_file() && is_vm(&entry) {
+                    entries.push(entry);
+                }
+            }
+            if entries.is_empty() {
+                return Err(format!("{}: no .vm files", path.display()).into());
             }
+            entries.sort();
+            files.extend(entries);
+        } else if is_vm(path) {
+            files.push(path.to_path_buf());
+        } else {
+            return Err(format!("{}: not a .vm file or directory", path.display()).into());
         }
-    } else {
-        input_files.push(config.in_file.clone());
     }
 
-    code_writer.write_init();
+    // Static variables are named after the file, so file names must be unique.
+    let mut names: Vec<String> = Vec::new();
+    for file in &files {
+        let name = file_name(file);
+        if names.contains(&name) {
+            return Err(format!(
+                "{}: another {}.vm is already included",
+                file.display(),
+                name
+            )
+            .into());
+        }
+        names.push(name);
+    }
+
+    files.sort_by_key(|file| file_name(file) != "Sys");
+    Ok(files)
+}
+
+// The file name without directory and extension, e.g. `Main` for `Pong/Main.vm`.
+fn file_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// Assembles the translated program, blaming errors on the VM command they come from.
+pub fn assemble(asm: &str, source_map: &[SourceEntry]) -> Result<Vec<u16>, Box<dyn Error>> {
+    assembler::assemble(asm).map_err(|err| {
+        let entry = err.address.and_then(|address| {
+            source_map
+                .iter()
+                .find(|entry| (entry.address..entry.address + entry.length).contains(&address))
+        });
+        match entry {
+            Some(entry) => format!(
+                "{}:{}: '{}' in {}: {}",
+                entry.file, entry.line, entry.command, entry.function, err
+            )
+            .into(),
+            None => err.into(),
+        }
+    })
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let mut code_writer = CodeWriter::build();
+    code_writer.set_compact(config.compact);
+    code_writer.set_annotate(config.annotate);
+    code_writer.set_cache_top(config.cache_top);
+    let input_files = input_files(&config.in_files)?;
+
+    let mut parsers = Vec::new();
+    for in_file in input_files {
+        let in_file_name = in_file.to_string_lossy();
+        println!("Loading file: {}", in_file_name);
+        let parser = Parser::build(&in_file_name)?;
+        if config.check_labels {
+            check_labels(&in_file_name, parser.commands())?;
+        }
+        parsers.push((file_name(&in_file), parser));
+    }
 
-    while let Some(in_file) = input_files.pop() {
-        println!("Loading file: {}", in_file.as_str());
-        let mut parser = Parser::build(in_file.as_str()).unwrap();
-   
-        let in_file_name = in_file.replace(".vm", "");
-        code_writer.set_file_name(in_file_name.split('/').last().unwrap());
+    if config.verify {
+        verifier::verify(&parsers)?;
+    }
+    if config.inline {
+        println!("Inlined {} calls", inliner::inline(&mut parsers));
+    }
+    if config.optimize {
+        for (_, parser) in &mut parsers {
+            parser.optimize();
+        }
+    }
 
-        loop {
-            if !parser.has_more_lines() {
-                break;
+    let reachable = match config.prune {
+        true => {
+            let files = parsers.iter().map(|(_, parser)| parser.commands());
+            let reachable = reachable_functions(files, "Sys.init");
+            if reachable.is_empty() {
+                return Err("--prune needs a Sys.init function to start from".into());
             }
-            parser.advance();
+            Some(reachable)
+        }
+        false => None,
+    };
+
+    if config.bootstrap {
+        code_writer.write_init();
+    }
+
+    let mut pruned = 0;
+    for (file_name, mut parser) in parsers {
+        code_writer.set_file_name(&file_name);
 
-            if parser.command_type() == CommandType::CPush
-                || parser.command_type() == CommandType::CPop
+        // Code before the first function is always kept.
+        let mut live = true;
+        while parser.has_more_lines() {
+            parser.advance();
+            if let (VmCommand::Function(name, _), Some(reachable)) = (parser.command(), &reachable)
             {
-                code_writer.write_push_pop(parser.command_type(), &parser.arg1(), parser.arg2());
-            } else if parser.command_type() == CommandType::CArithmetic {
-                code_writer.write_arithmetic(parser.arg1().as_str());
-            } else if parser.command_type() == CommandType::CLabel {
-                code_writer.write_label(parser.arg1().as_str());
-            } else if parser.command_type() == CommandType::CIf {
-                code_writer.write_if(parser.arg1().as_str());
-            } else if parser.command_type() == CommandType::CGoto {
-                code_writer.write_goto(parser.arg1().as_str());
-            } else if parser.command_type() == CommandType::CFunction {
-                code_writer.write_function(parser.arg1().as_str(), parser.arg2());
-            } else if parser.command_type() == CommandType::CReturn {
-                code_writer.write_return();
-            } else if parser.command_type() == CommandType::CCall {
-                code_writer.write_call(parser.arg1().as_str(), parser.arg2());
-            } else {
-                panic!("not implemented yet!");
+                live = reachable.contains(name);
+                if !live {
+                    pruned += 1;
+                }
+            }
+            if live {
+                code_writer.write_source_command(parser.command(), parser.line());
             }
         }
     }
+    if config.prune {
+        println!("Pruned {} unreachable functions", pruned);
+    }
+
+    code_writer.write_routines();
 
-    println!("Output: {}", config.out_file);
+    let out_path = Path::new(&config.out_file);
+    let mut outputs = vec![(out_path.to_path_buf(), String::from(code_writer.output()))];
+    if out_path.extension().is_some_and(|ext| ext == "hack") {
+        let undefined = code_writer.undefined_targets();
+        if !undefined.is_empty() {
+            return Err(undefined.join("\n").into());
+        }
+        let words = assemble(code_writer.output(), code_writer.source_map())?;
+        let hack: String = words
+            .iter()
+            .map(|word| format!("{:016b}\n", word))
+            .collect();
+        outputs[0].1 = hack;
+        if config.keep_asm {
+            let asm = String::from(code_writer.output());
+            outputs.push((out_path.with_extension("asm"), asm));
+        }
+    }
+    for (path, contents) in outputs {
+        fs::write(&path, contents).map_err(|err| format!("{}: {}", path.display(), err))?;
+        println!("Output: {}", path.display());
+    }
+
+    if config.source_map {
+        let map_file = Path::new(&config.out_file).with_extension("map.json");
+        fs::write(&map_file, source_map_json(code_writer.source_map()))
+            .map_err(|err| format!("{}: {}", map_file.display(), err))?;
+        println!("Source map: {}", map_file.display());
+    }
 
     Ok(())
 }
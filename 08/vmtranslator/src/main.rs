@@ -5,7 +5,7 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     let config = Config::build(&args).unwrap_or_else(|err| {
         println!("Problem parsing arguments: {err}");
-        println!("Program format: <input asm path> <output hack path>");
+        println!("Program format: <input vm file or directory>... <output asm or hack path> [--keep-asm] [--no-bootstrap] [--check-labels] [--compact] [--annotate] [--source-map] [--prune] [--optimize] [--cache-top] [--inline] [--verify]");
         process::exit(1);
     });
 
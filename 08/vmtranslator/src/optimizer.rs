@@ -0,0 +1,73 @@
+use crate::{ArithOp, Segment::Constant, VmCommand};
+
+// Peephole optimizations on the commands of one file, each rewritten command keeps the
+// line of its first command. Labels end every pattern, so no jump lands inside one.
+pub fn optimize(commands: &[(usize, VmCommand)]) -> Vec<(usize, VmCommand)> {
+    let mut optimized: Vec<(usize, VmCommand)> = Vec::new();
+    for command in commands {
+        optimized.push(command.clone());
+        // A rewrite can complete another pattern, e.g. folded constants popped right away.
+        while rewrite(&mut optimized) {}
+    }
+    optimized
+}
+
+// Replaces the commands at the end that match a pattern, returns whether any did.
+fn rewrite(commands: &mut Vec<(usize, VmCommand)>) -> bool {
+    use VmCommand::{Arithmetic, IfGoto, Pop, Push};
+
+    let (matched, replacement) = match commands.as_slice() {
+        [.., (line, Push(Constant, x)), (_, Push(Constant, y)), (_, Arithmetic(op))]
+            if fold(*op, *x, *y).is_some() =>
+        {
+            let value = fold(*op, *x, *y).unwrap();
+            (3, Some((*line, Push(Constant, value))))
+        }
+        [.., (_, Arithmetic(ArithOp::Not)), (_, Arithmetic(ArithOp::Not))] => (2, None),
+        [.., (_, Push(from, i)), (_, Pop(to, j))] if from == to && i == j => (2, None),
+        [.., (line, Push(from, i)), (_, Pop(to, j))] => {
+            (2, Some((*line, VmCommand::Move(*from, *i, *to, *j))))
+        }
+        [.., (line, Push(Constant, 0)), (_, Arithmetic(ArithOp::Eq))] => {
+            (2, Some((*line, VmCommand::IsZero)))
+        }
+        [.., (line, Arithmetic(op)), (_, Arithmetic(ArithOp::Not)), (_, IfGoto(label))]
+            if is_comparison(*op) =>
+        {
+            (
+                3,
+                Some((*line, VmCommand::IfCompare(*op, true, label.clone()))),
+            )
+        }
+        [.., (line, Arithmetic(op)), (_, IfGoto(label))] if is_comparison(*op) => (
+            2,
+            Some((*line, VmCommand::IfCompare(*op, false, label.clone()))),
+        ),
+        _ => return false,
+    };
+    commands.truncate(commands.len() - matched);
+    commands.extend(replacement);
+    true
+}
+
+fn is_comparison(op: ArithOp) -> bool {
+    matches!(op, ArithOp::Eq | ArithOp::Gt | ArithOp::Lt)
+}
+
+// `push constant x; push constant y; op` as one constant, if it is one `push constant` can
+// take. True comparisons are -1, so only false ones fold.
+fn fold(op: ArithOp, x: u16, y: u16) -> Option<u16> {
+    let (x, y) = (x as i16, y as i16);
+    let value = match op {
+        ArithOp::Add => x.wrapping_add(y),
+        ArithOp::Sub => x.wrapping_sub(y),
+        ArithOp::And => x & y,
+        ArithOp::Or => x | y,
+        ArithOp::Eq => -((x == y) as i16),
+        ArithOp::Gt => -((x > y) as i16),
+        ArithOp::Lt => -((x < y) as i16),
+        // Unary ops only take y and are not part of this pattern.
+        ArithOp::Neg | ArithOp::Not => return None,
+    };
+    u16::try_from(value).ok()
+}
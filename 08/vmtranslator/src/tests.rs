@@ -0,0 +1,467 @@
+use crate::{
+    assemble, check_labels, file_name, inliner, input_files, optimizer, parse_command,
+    reachable_functions, source_map_json, verifier, ArithOp, CodeWriter, Parser, Segment,
+    VmCommand,
+};
+use cpuemulator::cpu::Cpu;
+
+// Translates the files in order with the options `setup` sets, like `run` does.
+fn translate(
+    files: &[(String, Parser)],
+    bootstrap: bool,
+    setup: impl Fn(&mut CodeWriter),
+) -> String {
+    let mut code_writer = CodeWriter::build();
+    setup(&mut code_writer);
+    if bootstrap {
+        code_writer.write_init();
+    }
+    for (file_name, parser) in files {
+        code_writer.set_file_name(file_name);
+        for (_, command) in parser.commands() {
+            code_writer.write_command(command);
+        }
+    }
+    code_writer.write_routines();
+    String::from(code_writer.output())
+}
+
+fn load(dir: &str) -> Vec<(String, Parser)> {
+    input_files(&[dir.to_string()])
+        .unwrap()
+        .iter()
+        .map(|path| {
+            (
+                file_name(path),
+                Parser::build(&path.to_string_lossy()).unwrap(),
+            )
+        })
+        .collect()
+}
+
+// Assembles the program and runs it until it halts.
+fn execute(asm: &str) -> Cpu {
+    let mut cpu = Cpu::build(&assemble(asm, &[]).unwrap());
+    cpu.run(1_000_000);
+    assert!(cpu.halted);
+    cpu
+}
+
+#[test]
+fn test_parse_commands() {
+    let cases = [
+        ("push local 2", VmCommand::Push(Segment::Local, 2)),
+        ("pop pointer 1 // that", VmCommand::Pop(Segment::Pointer, 1)),
+        ("  lt", VmCommand::Arithmetic(ArithOp::Lt)),
+        (
+            "if-goto WHILE_END0",
+            VmCommand::IfGoto(String::from("WHILE_END0")),
+        ),
+        (
+            "function Main.main 3",
+            VmCommand::Function(String::from("Main.main"), 3),
+        ),
+        (
+            "call Output.printInt 1",
+            VmCommand::Call(String::from("Output.printInt"), 1),
+        ),
+        ("return", VmCommand::Return),
+    ];
+    for (line, command) in cases {
+        assert_eq!(parse_command(line), Ok(Some(command.clone())));
+        assert_eq!(parse_command(&command.to_string()), Ok(Some(command)));
+    }
+    assert_eq!(parse_command("   // comment"), Ok(None));
+}
+
+#[test]
+fn test_parse_errors() {
+    let source = "push constant 1\npop constant 0\npush pointer 2\npush temp 8\nfoo\n\
+                  push local x\nlabel 1abc\nadd 3\ncall\npush constant 32768\n\
+                  label -1\n";
+    let err = Parser::parse("Bad.vm", source).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Bad.vm:2: cannot pop to the constant segment\n\
+         Bad.vm:3: index 2 is out of range for pointer (0..1)\n\
+         Bad.vm:4: index 8 is out of range for temp (0..7)\n\
+         Bad.vm:5: unknown command 'foo'\n\
+         Bad.vm:6: invalid index 'x'\n\
+         Bad.vm:7: invalid label '1abc'\n\
+         Bad.vm:8: unexpected '3' after 'add'\n\
+         Bad.vm:9: missing function name after 'call'\n\
+         Bad.vm:10: index 32768 is out of range for constant (0..32767)\n\
+         Bad.vm:11: invalid label '-1'"
+    );
+}
+
+#[test]
+fn test_labels_are_scoped_to_functions() {
+    let source = "function A.f 0\nlabel LOOP\ngoto LOOP\n\
+                  function B.g 0\nlabel LOOP\nif-goto LOOP\ncall A.f 0\n";
+    let parser = Parser::parse("Test.vm", source).unwrap();
+    check_labels("Test.vm", parser.commands()).unwrap();
+
+    let mut code_writer = CodeWriter::build();
+    for (_, command) in parser.commands() {
+        code_writer.write_command(command);
+    }
+    let output = code_writer.output();
+    assert!(output.contains("(A.f$LOOP)\n@A.f$LOOP\n0;JMP\n"));
+    assert!(output.contains("(B.g$LOOP)\n"));
+    assert!(output.contains("@B.g$LOOP\nD;JNE\n"));
+    assert!(output.contains("@A.f\n0;JMP\n"));
+
+    let source = "function A.f 0\nlabel END\nlabel END\nfunction B.g 0\ngoto END\n";
+    let parser = Parser::parse("Test.vm", source).unwrap();
+    let err = check_labels("Test.vm", parser.commands()).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Test.vm:3: duplicate label END\nTest.vm:5: no label END in function B.g"
+    );
+}
+
+#[test]
+fn test_comparisons_use_symbolic_labels() {
+    let mut code_writer = CodeWriter::build();
+    code_writer.set_file_name("Main");
+    code_writer.write_arithmetic(ArithOp::Eq);
+    code_writer.write_arithmetic(ArithOp::Lt);
+    let output = code_writer.output();
+    assert!(output.contains("M=-1\n@Main$cmp.0\nD;JEQ\n@SP\nA=M\nM=0\n(Main$cmp.0)\n"));
+    assert!(output.contains("@Main$cmp.1\nD;JLT\n"));
+    // No jump targets are ROM addresses.
+    assert!(!output
+        .lines()
+        .any(|line| line.starts_with("@") && line[1..].parse::<u16>().is_ok()));
+}
+
+#[test]
+fn test_compact_shares_routines() {
+    let source = "function Main.main 0\npush constant 1\npush constant 2\nlt\ncall Main.f 1\n\
+                  call Main.f 1\nreturn\nfunction Main.f 0\npush argument 0\nreturn\n";
+    let files = [(
+        String::from("Main"),
+        Parser::parse("Main.vm", source).unwrap(),
+    )];
+    let inline = translate(&files, false, |_| {});
+    let compact = translate(&files, false, |code_writer| code_writer.set_compact(true));
+    assert!(!inline.contains("VM$"));
+    for routine in ["(VM$call)", "(VM$return)", "(VM$lt)", "(VM$end)"] {
+        assert_eq!(compact.matches(routine).count(), 1);
+    }
+    assert_eq!(compact.matches("@VM$call\n0;JMP\n").count(), 2);
+    assert_eq!(compact.matches("@VM$return\n0;JMP\n").count(), 2);
+    assert!(!compact.contains("(VM$eq)"));
+    assert!(compact.lines().count() < inline.lines().count());
+}
+
+#[test]
+fn test_compact_runs_fibonacci_element() {
+    let files = load("../FunctionCalls/FibonacciElement");
+    let inline = assemble(&translate(&files, true, |_| {}), &[]).unwrap();
+    let compact = translate(&files, true, |code_writer| code_writer.set_compact(true));
+    assert!(assemble(&compact, &[]).unwrap().len() < inline.len());
+
+    let cpu = execute(&compact);
+    assert_eq!((cpu.ram[0], cpu.ram[261]), (262, 3));
+}
+
+#[test]
+fn test_return_labels_belong_to_the_caller() {
+    let sys = "function Sys.init 0\npush constant 3\ncall Main.f 1\npush constant 4\n\
+               call Main.f 1\nadd\npop temp 0\nlabel HALT\ngoto HALT\n";
+    // Labels named like the return addresses of the two calls.
+    let main = "function Main.f 0\ngoto ret.2\nlabel ret.1\npush argument 0\nreturn\n\
+                label ret.2\ngoto ret.1\n";
+    let files = [
+        (String::from("Sys"), Parser::parse("Sys.vm", sys).unwrap()),
+        (
+            String::from("Main"),
+            Parser::parse("Main.vm", main).unwrap(),
+        ),
+    ];
+    for compact in [false, true] {
+        let asm = translate(&files, true, |code_writer| code_writer.set_compact(compact));
+        assert!(asm.contains("(VM$ret.0)\n"));
+        assert!(asm.contains("(Sys.init$ret.1)\n"));
+        assert_eq!(execute(&asm).ram[5], 7);
+    }
+}
+
+#[test]
+fn test_input_files_start_with_sys() {
+    let dir = "../FunctionCalls/StaticsTest";
+    let files = input_files(&[dir.to_string()]).unwrap();
+    let names: Vec<String> = files
+        .iter()
+        .map(|file| file.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names, ["Sys.vm", "Class1.vm", "Class2.vm"]);
+
+    let sys = format!("{}/Sys.vm", dir);
+    assert!(input_files(&[dir.to_string(), sys]).is_err());
+    assert!(input_files(&[format!("{}/StaticsTest.tst", dir)]).is_err());
+}
+
+#[test]
+fn test_annotations_and_source_map() {
+    let source = "function Main.main 0\nlabel LOOP\npush constant 7\ngoto LOOP\n";
+    let parser = Parser::parse("Main.vm", source).unwrap();
+    let mut code_writer = CodeWriter::build();
+    code_writer.set_annotate(true);
+    code_writer.set_file_name("Main");
+    for (line, command) in parser.commands() {
+        code_writer.write_source_command(command, *line);
+    }
+
+    assert!(code_writer
+        .output()
+        .starts_with("// function Main.main 0  [Main.vm:1]\n(Main.main)\n"));
+    assert!(code_writer
+        .output()
+        .contains("// push constant 7  [Main.vm:3]\n@7\n"));
+    // The label has no code and the function none without locals.
+    let map = code_writer.source_map();
+    assert_eq!(map.len(), 2);
+    assert_eq!((map[0].address, map[0].line), (0, 3));
+    assert_eq!((map[1].address, map[1].length), (map[0].length, 2));
+    assert_eq!(map[1].function, "Main.main");
+    assert!(source_map_json(map).contains(
+        "{\"address\": 0, \"length\": 7, \"file\": \"Main.vm\", \"line\": 3, \
+         \"function\": \"Main.main\", \"command\": \"push constant 7\"}"
+    ));
+}
+
+#[test]
+fn test_assembly_errors_name_the_vm_command() {
+    let parser = Parser::parse("Main.vm", "function Main.main 0\npush constant 7\nneg\n").unwrap();
+    let mut code_writer = CodeWriter::build();
+    code_writer.set_file_name("Main");
+    for (line, command) in parser.commands() {
+        code_writer.write_source_command(command, *line);
+    }
+    let words = assemble(code_writer.output(), code_writer.source_map()).unwrap();
+    assert_eq!(words[0], 7);
+    let length: usize = code_writer
+        .source_map()
+        .iter()
+        .map(|entry| entry.length)
+        .sum();
+    assert_eq!(words.len(), length);
+
+    // Break the instruction after `@SP` in the code of neg.
+    let broken = code_writer.output().replacen("M=-M", "M=-Q", 1);
+    let err = assemble(&broken, code_writer.source_map()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Main.vm:3: 'neg' in Main.main: instruction 9: invalid computation: -Q"
+    );
+}
+
+#[test]
+fn test_undefined_jump_targets() {
+    let source = "function Main.main 0\nlabel LOOP\ngoto NOWHERE\ncall Missing.f 0\n\
+                  call Main.main 0\nif-goto LOOP\n";
+    let parser = Parser::parse("Main.vm", source).unwrap();
+    let mut code_writer = CodeWriter::build();
+    code_writer.set_file_name("Main");
+    code_writer.write_init();
+    for (line, command) in parser.commands() {
+        code_writer.write_source_command(command, *line);
+    }
+    assert_eq!(
+        code_writer.undefined_targets(),
+        [
+            "no function Sys.init",
+            "Main.vm:3: 'goto NOWHERE' in Main.main: no label NOWHERE in function Main.main",
+            "Main.vm:4: 'call Missing.f 0' in Main.main: no function Missing.f",
+        ]
+    );
+}
+
+#[test]
+fn test_reachable_functions() {
+    let sys = "function Sys.init 0\ncall Main.main 0\nlabel HALT\ngoto HALT\n";
+    let main = "function Main.main 0\ncall Main.loop 0\nreturn\nfunction Main.loop 0\n\
+                call Main.loop 0\ncall Math.abs 1\nreturn\nfunction Main.unused 0\n\
+                call Main.main 0\nreturn\n";
+    let sys = Parser::parse("Sys.vm", sys).unwrap();
+    let main = Parser::parse("Main.vm", main).unwrap();
+
+    let mut reachable: Vec<String> =
+        reachable_functions([sys.commands(), main.commands()], "Sys.init")
+            .into_iter()
+            .collect();
+    reachable.sort();
+    assert_eq!(reachable, ["Main.loop", "Main.main", "Sys.init"]);
+    assert!(reachable_functions([main.commands()], "Sys.init").is_empty());
+}
+
+#[test]
+fn test_optimize() {
+    let source = "push constant 2\npush constant 3\nadd\npop local 0\n\
+                  push local 1\npop local 1\n\
+                  push argument 0\nnot\nnot\npush constant 0\neq\n\
+                  label LOOP\npush local 0\npush argument 1\nlt\nnot\nif-goto LOOP\n\
+                  push constant 1\npush constant 2\nlt\npush constant 0\npush constant 0\neq\n";
+    let parser = Parser::parse("Main.vm", source).unwrap();
+    let optimized = optimizer::optimize(parser.commands());
+
+    let expected = [
+        (1, VmCommand::Move(Segment::Constant, 5, Segment::Local, 0)),
+        (7, VmCommand::Push(Segment::Argument, 0)),
+        (10, VmCommand::IsZero),
+        (12, VmCommand::Label(String::from("LOOP"))),
+        (13, VmCommand::Push(Segment::Local, 0)),
+        (14, VmCommand::Push(Segment::Argument, 1)),
+        (
+            15,
+            VmCommand::IfCompare(ArithOp::Lt, true, String::from("LOOP")),
+        ),
+        // True is -1, which `push constant` cannot take.
+        (18, VmCommand::Push(Segment::Constant, 1)),
+        (19, VmCommand::Push(Segment::Constant, 2)),
+        (20, VmCommand::Arithmetic(ArithOp::Lt)),
+        (21, VmCommand::Push(Segment::Constant, 0)),
+        (22, VmCommand::IsZero),
+    ];
+    assert_eq!(optimized, expected);
+    assert_eq!(optimized[6].1.to_string(), "lt; not; if-goto LOOP");
+}
+
+#[test]
+fn test_cache_top_spills_at_labels() {
+    let source = "push argument 0\npush argument 1\nadd\npop local 0\npush local 0\nlabel LOOP\n\
+                  push constant 1\nsub\nif-goto LOOP\n";
+    let files = [(
+        String::from("Main"),
+        Parser::parse("Main.vm", source).unwrap(),
+    )];
+    let cached = translate(&files, false, |code_writer| code_writer.set_cache_top(true));
+    // argument 0 is spilled by the second push, the sum goes from D to local 0.
+    assert!(cached.starts_with("@0\nD=A\n@ARG\nA=M+D\nD=M\n@SP\nA=M\nM=D\n@SP\nM=M+1\n@1\n"));
+    assert!(cached.contains("AM=M-1\nD=D+M\n@LCL\nA=M\nM=D\n"));
+    // local 0 is on the stack in RAM when the loop starts.
+    assert!(cached.contains("@SP\nA=M\nM=D\n@SP\nM=M+1\n(LOOP)\n@1\nD=A\n"));
+    let plain = translate(&files, false, |_| {});
+    assert!(cached.matches("@SP").count() < plain.matches("@SP").count());
+}
+
+#[test]
+fn test_cache_top_runs_like_the_stack() {
+    for dir in ["FibonacciElement", "NestedCall", "StaticsTest"] {
+        let dir = format!("../FunctionCalls/{}", dir);
+        let plain = execute(&translate(&load(&dir), true, |_| {}));
+        let mut files = load(&dir);
+        let cached = execute(&translate(&files, true, |code_writer| {
+            code_writer.set_cache_top(true)
+        }));
+        // The optimizer's moves and jumps take other paths through the cache.
+        for (_, parser) in &mut files {
+            parser.optimize();
+        }
+        let optimized = execute(&translate(&files, true, |code_writer| {
+            code_writer.set_cache_top(true)
+        }));
+        // R13 to R15 are scratch registers, above SP is garbage.
+        let sp = plain.ram[0] as usize;
+        for cpu in [cached, optimized] {
+            assert_eq!(cpu.ram[..13], plain.ram[..13], "{}", dir);
+            assert_eq!(cpu.ram[16..sp], plain.ram[16..sp], "{}", dir);
+        }
+    }
+}
+
+#[test]
+fn test_inline_accessor() {
+    let bat = "function Bat.getLeft 0\npush argument 0\npop pointer 0\npush this 0\nreturn\n\
+               function Bat.loop 0\nlabel LOOP\ngoto LOOP\n";
+    let main = "function Main.main 0\npush static 0\ncall Bat.getLeft 1\ncall Bat.loop 0\nreturn\n";
+    let mut files = vec![
+        (String::from("Bat"), Parser::parse("Bat.vm", bat).unwrap()),
+        (
+            String::from("Main"),
+            Parser::parse("Main.vm", main).unwrap(),
+        ),
+    ];
+    assert_eq!(inliner::inline(&mut files), 1);
+
+    // this 0 of the argument replaces it, THIS is saved below and restored.
+    let commands: Vec<VmCommand> = files[1].1.commands()[2..9]
+        .iter()
+        .map(|(line, command)| {
+            assert_eq!(*line, 3);
+            command.clone()
+        })
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            VmCommand::Push(Segment::Pointer, 0),
+            VmCommand::PushStack(2),
+            VmCommand::Pop(Segment::Pointer, 0),
+            VmCommand::Push(Segment::This, 0),
+            VmCommand::PushStack(2),
+            VmCommand::Pop(Segment::Pointer, 0),
+            VmCommand::Collapse(2),
+        ]
+    );
+    // Bat.loop never returns.
+    assert_eq!(
+        files[1].1.commands()[9].1,
+        VmCommand::Call(String::from("Bat.loop"), 0)
+    );
+}
+
+#[test]
+fn test_verify_reports_every_finding() {
+    let main = "function Main.main 0\npush constant 1\nif-goto SKIP\npush constant 2\n\
+                label SKIP\nreturn\nfunction Main.f 0\npush argument 1\ngoto MISSING\n\
+                function Main.g 0\ncall Main.f 1\ncall Math.multiply 1\ncall Main.h 0\n\
+                pop temp 0\nreturn\n";
+    let files = [(
+        String::from("Main"),
+        Parser::parse("Main.vm", main).unwrap(),
+    )];
+    let errors = verifier::verify(&files).unwrap_err().to_string();
+    assert_eq!(
+        errors.lines().collect::<Vec<_>>(),
+        [
+            "Main.vm:6: return without a value on the stack",
+            "Main.vm:5: 'label SKIP' is reached with 0 values on the stack from line 3 and 1 from line 4",
+            "Main.vm:9: no label MISSING in function Main.f",
+            "Main.vm:15: return without a value on the stack",
+            "Main.vm:11: Main.f uses 2 arguments but is called with 1",
+            "Main.vm:12: Math.multiply takes 2 arguments but is called with 1",
+            "Main.vm:13: no function Main.h",
+        ]
+    );
+
+    // Ending in a loop is fine, the call still needs a function.
+    let sys = "function Sys.init 0\npush constant 4\ncall Main.fibonacci 1\nlabel WHILE\n\
+               goto WHILE\n";
+    let files = [(String::from("Sys"), Parser::parse("Sys.vm", sys).unwrap())];
+    assert_eq!(
+        verifier::verify(&files).unwrap_err().to_string(),
+        "Sys.vm:3: no function Main.fibonacci"
+    );
+}
+
+#[test]
+fn test_inline_label_reached_by_a_later_jump() {
+    let sys = "function Sys.init 0\npush constant 7000\npop pointer 1\ncall Main.f 0\n\
+               pop that 0\nlabel HALT\ngoto HALT\n";
+    let main = "function Main.f 0\ngoto B\nlabel A\npush constant 42\nreturn\nlabel B\ngoto A\n";
+    let mut files = vec![
+        (String::from("Sys"), Parser::parse("Sys.vm", sys).unwrap()),
+        (
+            String::from("Main"),
+            Parser::parse("Main.vm", main).unwrap(),
+        ),
+    ];
+    assert_eq!(inliner::inline(&mut files), 1);
+
+    let cpu = execute(&translate(&files, true, |_| {}));
+    assert_eq!(cpu.ram[7000], 42);
+}
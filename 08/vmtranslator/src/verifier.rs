@@ -0,0 +1,248 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+};
+
+use crate::{Parser, Segment, VmCommand};
+
+// The functions of the Jack OS with their argument counts, for programs translated
+// without the OS .vm files.
+const OS_FUNCTIONS: &[(&str, u16)] = &[
+    ("Array.new", 1),
+    ("Array.dispose", 1),
+    ("Keyboard.init", 0),
+    ("Keyboard.keyPressed", 0),
+    ("Keyboard.readChar", 0),
+    ("Keyboard.readLine", 1),
+    ("Keyboard.readInt", 1),
+    ("Math.init", 0),
+    ("Math.abs", 1),
+    ("Math.multiply", 2),
+    ("Math.divide", 2),
+    ("Math.min", 2),
+    ("Math.max", 2),
+    ("Math.sqrt", 1),
+    ("Memory.init", 0),
+    ("Memory.peek", 1),
+    ("Memory.poke", 2),
+    ("Memory.alloc", 1),
+    ("Memory.deAlloc", 1),
+    ("Output.init", 0),
+    ("Output.moveCursor", 2),
+    ("Output.printChar", 1),
+    ("Output.printString", 1),
+    ("Output.printInt", 1),
+    ("Output.println", 0),
+    ("Output.backSpace", 0),
+    ("Screen.init", 0),
+    ("Screen.clearScreen", 0),
+    ("Screen.setColor", 1),
+    ("Screen.drawPixel", 2),
+    ("Screen.drawLine", 4),
+    ("Screen.drawRectangle", 4),
+    ("Screen.drawCircle", 3),
+    ("String.new", 1),
+    ("String.dispose", 1),
+    ("String.length", 1),
+    ("String.charAt", 2),
+    ("String.setCharAt", 3),
+    ("String.appendChar", 2),
+    ("String.eraseLastChar", 1),
+    ("String.intValue", 1),
+    ("String.setInt", 2),
+    ("String.backSpace", 0),
+    ("String.doubleQuote", 0),
+    ("String.newLine", 0),
+    ("Sys.init", 0),
+    ("Sys.halt", 0),
+    ("Sys.error", 1),
+    ("Sys.wait", 1),
+];
+
+// A function or the code before the first function of a file.
+struct Scope<'a> {
+    file: &'a str,
+    name: Option<&'a str>,
+    commands: &'a [(usize, VmCommand)],
+}
+
+impl Scope<'_> {
+    fn report(&self, errors: &mut Vec<String>, idx: usize, message: String) {
+        errors.push(format!(
+            "{}.vm:{}: {}",
+            self.file, self.commands[idx].0, message
+        ));
+    }
+
+    fn describe(&self) -> String {
+        match self.name {
+            Some(name) => format!("in function {}", name),
+            None => String::from("outside of functions"),
+        }
+    }
+
+    // Follows every path from the start, with the depth of the stack above the frame
+    // before each command.
+    fn check_stack(&self, errors: &mut Vec<String>) {
+        let mut labels = HashMap::new();
+        for (idx, (_, command)) in self.commands.iter().enumerate() {
+            if let VmCommand::Label(label) = command {
+                labels.entry(label.as_str()).or_insert(idx);
+            }
+        }
+
+        // The depth before each command and the line of the command that led there.
+        let mut depths: Vec<Option<(i32, usize)>> = vec![None; self.commands.len()];
+        let mut pending = VecDeque::from([(0, 0, 0)]);
+        while let Some((idx, depth, from)) = pending.pop_front() {
+            if idx == self.commands.len() {
+                // Code outside of functions may end with values on the stack, like the tests
+                // of project 07.
+                if self.name.is_some() {
+                    self.report(
+                        errors,
+                        idx - 1,
+                        format!("{} ends without return", self.describe()),
+                    );
+                }
+                continue;
+            }
+            match depths[idx] {
+                Some((other, other_from)) if other != depth => {
+                    let (line, command) = &self.commands[idx];
+                    errors.push(format!(
+                        "{}.vm:{}: '{}' is reached with {} values on the stack from line {} and {} from line {}",
+                        self.file, line, command, other, other_from, depth, from
+                    ));
+                    continue;
+                }
+                Some(_) => continue,
+                None => depths[idx] = Some((depth, from)),
+            }
+
+            let (line, command) = &self.commands[idx];
+            let after = depth + command.stack_effect();
+            if after < 0 {
+                let message = match command {
+                    VmCommand::Return => String::from("return without a value on the stack"),
+                    command => format!("'{}' pops from an empty stack", command),
+                };
+                self.report(errors, idx, message);
+                continue;
+            }
+            let target = |label: &str| labels.get(label).copied();
+            match command {
+                VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                    match target(label) {
+                        Some(target) => pending.push_back((target, after, *line)),
+                        None => self.report(
+                            errors,
+                            idx,
+                            format!("no label {} {}", label, self.describe()),
+                        ),
+                    }
+                    if let VmCommand::IfGoto(_) = command {
+                        pending.push_back((idx + 1, after, *line));
+                    }
+                }
+                VmCommand::Return => {}
+                _ => pending.push_back((idx + 1, after, *line)),
+            }
+        }
+    }
+}
+
+// The argument count every function needs at least, from the highest argument it uses.
+fn argument_counts(scopes: &[Scope]) -> HashMap<String, u16> {
+    let mut counts = HashMap::new();
+    for scope in scopes {
+        let Some(name) = scope.name else {
+            continue;
+        };
+        let count = scope
+            .commands
+            .iter()
+            .filter_map(|(_, command)| match command {
+                VmCommand::Push(Segment::Argument, index)
+                | VmCommand::Pop(Segment::Argument, index) => Some(index + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        counts.insert(name.to_string(), count);
+    }
+    counts
+}
+
+// Checks the stack depth along every path, that gotos stay in their function and that calls
+// match a function of the input or the OS. Reports all findings as `File.vm:line: message`.
+// Segment indices are already checked by the parser.
+pub fn verify(files: &[(String, Parser)]) -> Result<(), Box<dyn Error>> {
+    // Each function starts at its `function` command, so none is empty.
+    let mut scopes = Vec::new();
+    for (file, parser) in files {
+        let commands = parser.commands();
+        if commands.is_empty() {
+            continue;
+        }
+        let mut starts: Vec<usize> = commands
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, command))| matches!(command, VmCommand::Function(..)))
+            .map(|(idx, _)| idx)
+            .collect();
+        // Code before the first function is its own scope.
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+        let ends = starts.iter().skip(1).copied().chain([commands.len()]);
+        for (start, end) in starts.iter().copied().zip(ends) {
+            let name = match &commands[start].1 {
+                VmCommand::Function(name, _) => Some(name.as_str()),
+                _ => None,
+            };
+            scopes.push(Scope {
+                file,
+                name,
+                commands: &commands[start..end],
+            });
+        }
+    }
+
+    let mut errors = Vec::new();
+    let counts = argument_counts(&scopes);
+    for scope in &scopes {
+        scope.check_stack(&mut errors);
+        for (idx, (_, command)) in scope.commands.iter().enumerate() {
+            let VmCommand::Call(callee, args) = command else {
+                continue;
+            };
+            if let Some(needed) = counts.get(callee) {
+                if args < needed {
+                    let message = format!(
+                        "{} uses {} arguments but is called with {}",
+                        callee, needed, args
+                    );
+                    scope.report(&mut errors, idx, message);
+                }
+                continue;
+            }
+            match OS_FUNCTIONS.iter().find(|(name, _)| name == callee) {
+                Some((_, expected)) if expected != args => {
+                    let message = format!(
+                        "{} takes {} arguments but is called with {}",
+                        callee, expected, args
+                    );
+                    scope.report(&mut errors, idx, message);
+                }
+                Some(_) => {}
+                None => scope.report(&mut errors, idx, format!("no function {}", callee)),
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+    Ok(())
+}